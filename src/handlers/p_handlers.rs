@@ -1,87 +1,579 @@
 use std::borrow::Cow;
+use std::time::Instant;
 
-use actix::{Addr, Handler, StreamHandler};
-use actix_web::http::header::Header;
-use actix_web::{get, post, web, Either, HttpRequest, HttpResponse, Responder};
+use actix::{ActorContext, ActorFutureExt, Addr, AsyncContext, Handler, StreamHandler, WrapFuture};
+use actix_web::error::InternalError;
+use actix_web::http::header::{
+    Accept, CacheControl, CacheDirective, ContentType, ETag, EntityTag, Header, IfNoneMatch, VARY,
+};
+use actix_web::middleware::Compress;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
 use actix_web_actors::ws;
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
 use base64::engine::general_purpose;
 use base64::Engine;
-use redis::Client;
+use chrono::Utc;
+use prometheus::{Encoder, TextEncoder};
+use serde_json::json;
+use tokio::sync::mpsc;
 
-use crate::models::err_models::VpError;
+use crate::middlewares::rate_middleware::IpRateLimit;
+use crate::models::cache_models::CanvasCache;
+use crate::models::challenge_models::ChallengeVerifier;
+use crate::models::cooldown_models::{UpdateCooldown, UpdateTier};
+use crate::models::err_models::{ErrorBody, VpError};
+use crate::models::generation_models::GenerationResponse;
+use crate::models::metrics_models::METRICS;
 use crate::models::p_models::{
-    AppState, CanvasResponse, PlaceUpdate, UpdatePixel, VpConnect, VpDisconnect, VpListener, VpRes,
-    VpSrv, WaitTime,
+    Announcement, AppState, CanvasFormat, CanvasLifecycle, CanvasQuery, CanvasResponse,
+    CanvasStateResponse, DiffQuery, GetPresence, PlaceReply, PlaceUpdate, Placement, Presence,
+    SysEvent, UpdatePixel, VpConnect, VpCount, VpDisconnect, VpEvent, VpListener, VpRes, VpSrv,
+    VpSseConnect, SSE_BUFFER, UPDATE_BACKLOG,
 };
+use crate::models::raid_models::{ModerationQuery, RaidDetector, UpdateShadowban};
+use crate::models::rate_models::{RateLimitConfig, RateLimited};
+use crate::models::redis_models::RedisManager;
 use crate::models::scylla_models::ScyllaManager;
-use crate::services::p_services::{diff_last_placed, reset_place, update_place};
+use crate::models::tile_models::{Tile, TileFormat, TileIndex, TileQuery, TileResponse};
+use crate::models::validation_models::FieldError;
+use crate::services::generation_services::get_generation;
+use crate::services::health_services::readiness;
+use crate::services::p_services::{
+    broadcast_event, current_generation, effective_cooldown, get_lifecycle, load_canvas,
+    place_pixel, reset_place, set_cooldown, set_lifecycle, set_tier, update_place,
+};
+use crate::services::raid_services::{moderation_queue, set_shadowban, watch_placement};
+use crate::services::rate_services::rate_limited;
 
-#[get("/canvas")]
+// cache lifetime (secs) of ended canvas generations
+const GENERATION_MAX_AGE: u32 = 86400;
+
+// json error body for rejected request payload, query or path
+pub fn invalid_request<E>(err: E, _req: &HttpRequest) -> actix_web::Error
+where
+    E: std::fmt::Debug + std::fmt::Display + 'static,
+{
+    let res = HttpResponse::BadRequest().json(ErrorBody::invalid_request(&err));
+    InternalError::from_response(err, res).into()
+}
+
+// admin endpoints need ADMIN_TOKEN as bearer token
+fn authorize(req: &HttpRequest, app_data: &AppState<'_>) -> Result<(), VpError> {
+    match Authorization::<Bearer>::parse(req) {
+        Ok(auth) if auth.as_ref().token().eq(&app_data.admin_token) => Ok(()),
+        _ => Err(VpError::Unauthorized),
+    }
+}
+
+// liveness, process is up and serving requests
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+// readiness, all dependencies reachable
+#[get("/readyz")]
+async fn readyz(
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    scylla: web::Data<ScyllaManager>,
+    pu_srv: web::Data<Addr<VpSrv<'_>>>,
+) -> impl Responder {
+    let res = readiness(&app_data, &redis, &scylla, &pu_srv).await;
+    if res.ready {
+        HttpResponse::Ok().json(res)
+    } else {
+        HttpResponse::ServiceUnavailable().json(res)
+    }
+}
+
+// prometheus text exposition of this instance's metrics
+#[get("/metrics")]
+async fn metrics() -> actix_web::Result<impl Responder> {
+    let encoder = TextEncoder::new();
+    let body = encoder
+        .encode_to_string(&METRICS.registry.gather())
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body))
+}
+
+#[get("/canvas", wrap = "Compress::default()")]
 async fn get_canvas(
+    req: HttpRequest,
+    query: web::Query<CanvasQuery>,
     app_data: web::Data<AppState<'_>>,
-    redis: web::Data<Client>,
+    redis: web::Data<RedisManager>,
+    cache: web::Data<CanvasCache>,
 ) -> actix_web::Result<impl Responder> {
-    let mut conn = redis
-        .get_tokio_connection_manager()
-        .await
-        .map_err(VpError::RedisErr)?;
-    let res = redis::Cmd::get(app_data.canvas_id.as_bytes())
-        .query_async::<_, Vec<u8>>(&mut conn)
-        .await
-        .map_err(VpError::RedisErr)?;
-    //base64 encode the bytearray
-    let resb64 = general_purpose::STANDARD_NO_PAD.encode(res);
-    Ok(HttpResponse::Ok().json(CanvasResponse {
-        id: app_data.canvas_id.as_ref(),
-        dim: app_data.canvas_dim,
-        canvas: &resb64,
-        cooldown: app_data.cooldown,
-    }))
+    let canvas = match cache.snapshot() {
+        Some(canvas) => canvas,
+        None => load_canvas(&app_data, &redis, &cache).await?,
+    };
+    let cooldown = effective_cooldown(None, None, &app_data, &redis).await?;
+    let format = query.format.unwrap_or_else(|| accepted_format(&req));
+    let etag = canvas_etag(format, canvas.version, cooldown);
+    let not_modified = not_modified(&req, &etag);
+    let mut res = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.insert_header(ETag(etag))
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .append_header((VARY, "Accept"));
+    if not_modified {
+        return Ok(res.finish());
+    }
+    Ok(match format {
+        CanvasFormat::Json => res.json(CanvasResponse {
+            id: app_data.canvas_id.as_ref(),
+            dim: app_data.canvas_dim,
+            canvas: &canvas.encoded,
+            cooldown,
+            version: canvas.version,
+        }),
+        CanvasFormat::Raw => res
+            .content_type(ContentType::octet_stream())
+            .insert_header(("X-Canvas-Id", app_data.canvas_id.as_ref()))
+            .insert_header(("X-Canvas-Dim", app_data.canvas_dim))
+            .insert_header(("X-Canvas-Version", canvas.version))
+            .insert_header(("X-Cooldown", cooldown))
+            .body(canvas.raw),
+    })
+}
+
+// pixels changed since a canvas version, 410 when client should refetch full canvas
+#[get("/canvas/diff", wrap = "Compress::default()")]
+async fn canvas_diff(
+    req: HttpRequest,
+    query: web::Query<DiffQuery>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    cache: web::Data<CanvasCache>,
+) -> actix_web::Result<impl Responder> {
+    let diff = match cache.diff(query.since) {
+        Some(diff) => diff,
+        None => {
+            load_canvas(&app_data, &redis, &cache).await?;
+            // still cold if canvas in redis doesn't match dimension
+            cache
+                .diff(query.since)
+                .unwrap_or(Err(VpError::DiffExpired(query.since)))
+        }
+    }?;
+    let format = query.format.unwrap_or_else(|| accepted_format(&req));
+    let mut res = HttpResponse::Ok();
+    res.insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .append_header((VARY, "Accept"));
+    Ok(match format {
+        CanvasFormat::Json => res.json(&diff),
+        CanvasFormat::Raw => res
+            .content_type(ContentType::octet_stream())
+            .insert_header(("X-Canvas-Version", diff.version))
+            .insert_header(("X-Diff-Since", diff.since))
+            .body(diff.raw()),
+    })
+}
+
+// weak tags, body differs with content-encoding : )
+fn canvas_etag(format: CanvasFormat, version: u64, cooldown: usize) -> EntityTag {
+    EntityTag::new_weak(match format {
+        CanvasFormat::Json => format!("{}-{}", version, cooldown),
+        CanvasFormat::Raw => format!("{}-{}-raw", version, cooldown),
+    })
+}
+
+// raw canvas only when client prefers octet-stream over json
+fn accepted_format(req: &HttpRequest) -> CanvasFormat {
+    if prefers(req, ContentType::octet_stream()) {
+        CanvasFormat::Raw
+    } else {
+        CanvasFormat::Json
+    }
+}
+
+// content type ranked first in Accept
+fn prefers(req: &HttpRequest, content_type: ContentType) -> bool {
+    Accept::parse(req).is_ok_and(|accept| accept.ranked().first() == Some(&content_type.0))
+}
+
+// If-None-Match matches current etag
+fn not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
+
+// per tile versions, clients refetch tiles whose version changed
+#[get("/canvas/tiles")]
+async fn canvas_tiles(
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    cache: web::Data<CanvasCache>,
+) -> actix_web::Result<impl Responder> {
+    let tiles = cache.tile_config();
+    let (version, versions) = match cache.tile_versions() {
+        Some(versions) => versions,
+        None => {
+            let canvas = load_canvas(&app_data, &redis, &cache).await?;
+            cache.tile_versions().unwrap_or_else(|| {
+                (
+                    canvas.version,
+                    vec![canvas.version; tiles.count(app_data.canvas_dim).pow(2) as usize],
+                )
+            })
+        }
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .json(TileIndex {
+            tile_size: tiles.size,
+            tiles: tiles.count(app_data.canvas_dim),
+            version,
+            versions,
+        }))
+}
+
+#[get("/canvas/tiles/{tx}/{ty}", wrap = "Compress::default()")]
+async fn canvas_tile(
+    req: HttpRequest,
+    path: web::Path<(u32, u32)>,
+    query: web::Query<TileQuery>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    cache: web::Data<CanvasCache>,
+) -> actix_web::Result<impl Responder> {
+    let (tx, ty) = path.into_inner();
+    let tiles = cache.tile_config();
+    if tiles.bounds(app_data.canvas_dim, tx, ty).is_none() {
+        Err(VpError::TileNotFound(tx, ty))?
+    }
+    let tile = match cache.tile(tx, ty) {
+        Some(tile) => tile,
+        None => {
+            let canvas = load_canvas(&app_data, &redis, &cache).await?;
+            match cache.tile(tx, ty) {
+                Some(tile) => tile,
+                None => Tile::extract(
+                    &canvas.raw,
+                    app_data.canvas_dim,
+                    tiles,
+                    (tx, ty),
+                    canvas.version,
+                )
+                .ok_or(VpError::CanvasSizeMismatch)?,
+            }
+        }
+    };
+    let format = query.format.unwrap_or_else(|| {
+        if prefers(&req, ContentType::png()) {
+            TileFormat::Png
+        } else if prefers(&req, ContentType::octet_stream()) {
+            TileFormat::Raw
+        } else {
+            TileFormat::Base64
+        }
+    });
+    let etag = EntityTag::new_weak(match format {
+        TileFormat::Raw => format!("{}-raw", tile.version),
+        TileFormat::Base64 => format!("{}", tile.version),
+        TileFormat::Png => format!("{}-png", tile.version),
+    });
+    let not_modified = not_modified(&req, &etag);
+    let mut res = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.insert_header(ETag(etag))
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .append_header((VARY, "Accept"));
+    if not_modified {
+        return Ok(res.finish());
+    }
+    Ok(match format {
+        TileFormat::Base64 => res.json(TileResponse {
+            tx: tile.tx,
+            ty: tile.ty,
+            x: tile.x,
+            y: tile.y,
+            width: tile.width,
+            height: tile.height,
+            version: tile.version,
+            tile: &general_purpose::STANDARD_NO_PAD.encode(&tile.raw),
+        }),
+        TileFormat::Raw => res
+            .content_type(ContentType::octet_stream())
+            .insert_header(("X-Tile-Width", tile.width))
+            .insert_header(("X-Tile-Height", tile.height))
+            .insert_header(("X-Tile-Version", tile.version))
+            .body(tile.raw),
+        TileFormat::Png => res
+            .content_type(ContentType::png())
+            .body(tile.png(&tiles.palette)?),
+    })
 }
 
 #[get("/vplace")]
+#[allow(clippy::too_many_arguments)]
 pub async fn vplace(
     req: HttpRequest,
+    app_data: web::Data<AppState<'static>>,
+    redis: web::Data<RedisManager>,
+    scylla: web::Data<ScyllaManager>,
+    rate_conf: web::Data<RateLimitConfig>,
+    verifier: web::Data<dyn ChallengeVerifier>,
+    detector: web::Data<RaidDetector>,
     srv_addr: web::Data<Addr<VpSrv<'_>>>,
     stream: web::Payload,
 ) -> impl Responder {
-    ws::start(VpListener::new(srv_addr.clone()), &req, stream)
+    let ip = rate_conf.client_ip(&req);
+    ws::start(
+        VpListener::new(
+            srv_addr.clone(),
+            app_data,
+            redis,
+            scylla,
+            rate_conf,
+            verifier,
+            detector,
+            ip,
+        ),
+        &req,
+        stream,
+    )
+}
+
+// Server-Sent Events alternative to /vplace
+#[get("/events")]
+pub async fn events(req: HttpRequest, srv_addr: web::Data<Addr<VpSrv<'_>>>) -> impl Responder {
+    let last_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<u64>().ok());
+    // room to replay the whole backlog on resume : )
+    let (tx, rx) = mpsc::channel(UPDATE_BACKLOG + SSE_BUFFER);
+    srv_addr.do_send(VpSseConnect { tx, last_id });
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, actix_web::Error>(event), rx))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(stream)
+}
+
+#[get("/challenge", wrap = "IpRateLimit")]
+pub async fn get_challenge(
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    verifier: web::Data<dyn ChallengeVerifier>,
+) -> actix_web::Result<impl Responder> {
+    match verifier.issue(&app_data, &redis).await? {
+        Some(challenge) => Ok(HttpResponse::Ok().json(challenge)),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+#[get("/canvas/state")]
+pub async fn canvas_state(
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    let lifecycle = get_lifecycle(&app_data, &redis).await?;
+    Ok(HttpResponse::Ok().json(CanvasStateResponse {
+        state: lifecycle.state_at(Utc::now().timestamp()),
+        start: lifecycle.start,
+        end: lifecycle.end,
+    }))
+}
+
+#[get("/stats/online")]
+pub async fn online_stats(
+    srv_addr: web::Data<Addr<VpSrv<'_>>>,
+) -> actix_web::Result<impl Responder> {
+    let presence = srv_addr
+        .send(GetPresence)
+        .await
+        .map_err(VpError::MailboxErr)?;
+    Ok(HttpResponse::Ok().json(presence))
 }
 
 #[get("/pixel/{x}/{y}")]
 pub async fn pixel_info(
     path: web::Path<(u32, u32)>,
     app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
     scylla: web::Data<ScyllaManager>,
 ) -> actix_web::Result<impl Responder> {
     let (x, y) = path.into_inner();
     if x < app_data.canvas_dim && y < app_data.canvas_dim {
-        let res = scylla.get_pixel(x, y).await;
-        match res {
-            Ok(pixel) => Ok(HttpResponse::Ok().json(pixel)),
-            Err(VpError::NoPixelData) => Ok(HttpResponse::NotFound().body("no Pixel Data Found")),
-            Err(e) => Err(e)?,
-        }
+        let generation = current_generation(&app_data, &redis).await?;
+        let pixel = scylla.get_pixel(x, y, generation).await?;
+        Ok(HttpResponse::Ok().json(pixel))
     } else {
         Err(VpError::CanvasSizeMismatch)?
     }
 }
 
+// final image and stats of a past (or current) canvas generation
+#[get("/canvases/{id}/generations/{n}", wrap = "Compress::default()")]
+async fn canvas_generation(
+    req: HttpRequest,
+    path: web::Path<(String, u64)>,
+    query: web::Query<CanvasQuery>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    let (id, n) = path.into_inner();
+    if id != app_data.canvas_id {
+        Err(VpError::CanvasNotFound)?
+    }
+    let generation = get_generation(n, &app_data, &redis).await?;
+    let mut res = HttpResponse::Ok();
+    // ended generations never change : )
+    res.insert_header(CacheControl(if generation.current {
+        vec![CacheDirective::NoCache]
+    } else {
+        vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(GENERATION_MAX_AGE),
+        ]
+    }))
+    .append_header((VARY, "Accept"));
+    Ok(
+        match query.format.unwrap_or_else(|| accepted_format(&req)) {
+            CanvasFormat::Json => res.json(GenerationResponse {
+                id: app_data.canvas_id.as_ref(),
+                dim: app_data.canvas_dim,
+                generation: generation.generation,
+                current: generation.current,
+                started_at: generation.started_at,
+                ended_at: generation.ended_at,
+                final_version: generation.final_version,
+                stats: &generation.stats,
+                canvas: &general_purpose::STANDARD_NO_PAD.encode(&generation.canvas),
+            }),
+            CanvasFormat::Raw => res
+                .content_type(ContentType::octet_stream())
+                .insert_header(("X-Canvas-Id", app_data.canvas_id.as_ref()))
+                .insert_header(("X-Canvas-Dim", app_data.canvas_dim))
+                .insert_header(("X-Canvas-Generation", generation.generation))
+                .body(generation.canvas),
+        },
+    )
+}
+
+// who placed a pixel last in a generation
+#[get("/canvases/{id}/generations/{n}/pixel/{x}/{y}")]
+async fn generation_pixel(
+    path: web::Path<(String, u64, u32, u32)>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    scylla: web::Data<ScyllaManager>,
+) -> actix_web::Result<impl Responder> {
+    let (id, n, x, y) = path.into_inner();
+    if id != app_data.canvas_id {
+        Err(VpError::CanvasNotFound)?
+    }
+    if x >= app_data.canvas_dim || y >= app_data.canvas_dim {
+        Err(VpError::CanvasSizeMismatch)?
+    }
+    if n > current_generation(&app_data, &redis).await? {
+        Err(VpError::GenerationNotFound(n))?
+    }
+    let pixel = scylla.get_pixel(x, y, n).await?;
+    Ok(HttpResponse::Ok().json(pixel))
+}
+
 #[get("/reset")]
 async fn reset_canvas(
     req: HttpRequest,
     app_data: web::Data<AppState<'_>>,
-    redis: web::Data<Client>,
+    redis: web::Data<RedisManager>,
     scylla: web::Data<ScyllaManager>,
+    pu_srv: web::Data<Addr<VpSrv<'_>>>,
 ) -> actix_web::Result<impl Responder> {
-    let auth = Authorization::<Bearer>::parse(&req)?.into_scheme();
-    if auth.token().eq(&app_data.admin_token) {
-        reset_place(&app_data, &redis, &scylla).await?;
-        Ok(HttpResponse::Ok())
-    } else {
-        Ok(HttpResponse::Unauthorized())
+    authorize(&req, &app_data)?;
+    let report = reset_place(&app_data, &redis, &scylla, &pu_srv).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[post("/admin/canvas/state")]
+async fn admin_canvas_state(
+    http_req: HttpRequest,
+    lifecycle: web::Json<CanvasLifecycle>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    let lifecycle = lifecycle.into_inner();
+    if let (Some(start), Some(end)) = (lifecycle.start, lifecycle.end) {
+        if start >= end {
+            Err(VpError::Validation(vec![FieldError::new(
+                "end",
+                "order",
+                "end should be after start".to_string(),
+            )]))?
+        }
     }
+    // transition is broadcast by lifecycle watcher of each instance : )
+    set_lifecycle(&lifecycle, &app_data, &redis).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/admin/cooldown")]
+async fn admin_cooldown(
+    http_req: HttpRequest,
+    update_req: web::Json<UpdateCooldown>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    pu_srv: web::Data<Addr<VpSrv<'_>>>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    set_cooldown(update_req.cooldown, &app_data, &redis, &pu_srv).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/admin/tier")]
+async fn admin_tier(
+    http_req: HttpRequest,
+    update_req: web::Json<UpdateTier>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    set_tier(
+        &update_req.uid,
+        update_req.tier.as_deref(),
+        &app_data,
+        &redis,
+    )
+    .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/admin/broadcast")]
+async fn admin_broadcast(
+    http_req: HttpRequest,
+    announcement: web::Json<Announcement>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+    pu_srv: web::Data<Addr<VpSrv<'_>>>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    let event = SysEvent::Announcement {
+        message: announcement.into_inner().message,
+    };
+    broadcast_event(event, &app_data, &redis, &pu_srv).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[post("/admin/pixel/update")]
@@ -89,38 +581,65 @@ async fn admin_update_pixel(
     http_req: HttpRequest,
     update_req: web::Json<UpdatePixel>,
     app_data: web::Data<AppState<'_>>,
-    redis: web::Data<Client>,
+    redis: web::Data<RedisManager>,
     scylla: web::Data<ScyllaManager>,
     pu_srv: web::Data<Addr<VpSrv<'_>>>,
 ) -> actix_web::Result<impl Responder> {
-    let auth = Authorization::<Bearer>::parse(&http_req)?.into_scheme();
-    if auth.token().eq(&app_data.admin_token) {
-        let u_req = update_req.into_inner();
-        update_place(&u_req, &app_data, &redis, &scylla, &pu_srv).await?;
-        Ok(HttpResponse::Ok())
-    } else {
-        Ok(HttpResponse::Unauthorized())
-    }
+    authorize(&http_req, &app_data)?;
+    let u_req = update_req.into_inner();
+    u_req.validate(app_data.canvas_dim, &app_data.uname_rules)?;
+    update_place(&u_req, &app_data, &redis, &scylla, &pu_srv).await?;
+    METRICS.placed("admin");
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/admin/moderation")]
+async fn admin_moderation(
+    http_req: HttpRequest,
+    query: web::Query<ModerationQuery>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    let flags = moderation_queue(query.count, &app_data, &redis).await?;
+    Ok(HttpResponse::Ok().json(flags))
 }
 
-#[post("/pixel/update")]
+#[post("/admin/shadowban")]
+async fn admin_shadowban(
+    http_req: HttpRequest,
+    ban_req: web::Json<UpdateShadowban>,
+    app_data: web::Data<AppState<'_>>,
+    redis: web::Data<RedisManager>,
+) -> actix_web::Result<impl Responder> {
+    authorize(&http_req, &app_data)?;
+    set_shadowban(&ban_req.uid, ban_req.banned, &app_data, &redis).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/pixel/update", wrap = "IpRateLimit")]
+#[allow(clippy::too_many_arguments)]
 async fn update_pixel(
+    http_req: HttpRequest,
     update_req: web::Json<UpdatePixel>,
     app_data: web::Data<AppState<'_>>,
-    redis: web::Data<Client>,
+    redis: web::Data<RedisManager>,
     scylla: web::Data<ScyllaManager>,
     pu_srv: web::Data<Addr<VpSrv<'_>>>,
+    verifier: web::Data<dyn ChallengeVerifier>,
+    rate_conf: web::Data<RateLimitConfig>,
+    detector: web::Data<RaidDetector>,
 ) -> actix_web::Result<impl Responder> {
     let u_req = update_req.into_inner();
-    let cooldown = i64::try_from(app_data.cooldown).map_err(VpError::ParseIntErr)?;
-    let time_diff: i64 = diff_last_placed(&u_req.uid, app_data.cooldown, &scylla).await?;
-    if time_diff.ge(&cooldown) {
-        update_place(&u_req, &app_data, &redis, &scylla, &pu_srv).await?;
-        Ok(Either::Left(HttpResponse::Ok()))
-    } else {
-        Ok(Either::Right(HttpResponse::Forbidden().json(WaitTime {
-            rem_wait: cooldown - time_diff,
-        })))
+    let placement = place_pixel(&u_req, &app_data, &redis, &scylla, &pu_srv, &**verifier).await?;
+    if let Placement::Placed(_) = placement {
+        let ip = rate_conf.client_ip(&http_req);
+        watch_placement(u_req.uid, u_req.loc, ip, &detector, &app_data, &redis).await;
+    }
+    match placement {
+        Placement::Placed(Some(credits)) => Ok(HttpResponse::Ok().json(credits)),
+        Placement::Placed(None) => Ok(HttpResponse::Ok().finish()),
+        Placement::Wait(wait) => Ok(HttpResponse::Forbidden().json(wait)),
     }
 }
 
@@ -128,17 +647,81 @@ async fn update_pixel(
 impl<'a> StreamHandler<Result<ws::Message, ws::ProtocolError>> for VpListener<'a> {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         use ws::Message::*;
-        if let Ok(Ping(msg)) = msg {
-            ctx.pong(&msg)
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::debug!("WebSocket protocol error : {}", e);
+                ctx.stop();
+                return;
+            }
+        };
+        self.hb = Instant::now();
+        match msg {
+            Ping(msg) => ctx.pong(&msg),
+            Pong(msg) => {
+                if let Ok(acked) = <[u8; 8]>::try_from(msg.as_ref()) {
+                    self.acked = u64::from_be_bytes(acked);
+                }
+            }
+            Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Text(text) => self.place(&text, ctx),
+            _ => {}
         }
     }
 }
+
+impl VpListener<'_> {
+    // pixel update sent over websocket, reply is sent only to this client : )
+    fn place(&self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let u_req = match serde_json::from_str::<UpdatePixel>(text) {
+            Ok(u_req) => u_req,
+            Err(e) => return reply(ctx, &PlaceReply::Error(ErrorBody::invalid_request(&e))),
+        };
+        let (app_data, redis, scylla, pu_srv, rate_conf, verifier, detector, ip) = (
+            self.app_data.clone(),
+            self.redis.clone(),
+            self.scylla.clone(),
+            self.srv_addr.clone(),
+            self.rate_conf.clone(),
+            self.verifier.clone(),
+            self.detector.clone(),
+            self.ip,
+        );
+        let fut = async move {
+            if let Some(retry_after) = rate_limited(ip, &rate_conf, &app_data, &redis).await {
+                return PlaceReply::RateLimited(RateLimited { retry_after });
+            }
+            match place_pixel(&u_req, &app_data, &redis, &scylla, &pu_srv, &**verifier).await {
+                Ok(Placement::Placed(credits)) => {
+                    watch_placement(u_req.uid, u_req.loc, ip, &detector, &app_data, &redis).await;
+                    PlaceReply::Placed { credits }
+                }
+                Ok(Placement::Wait(wait)) => PlaceReply::Wait(wait),
+                Err(e) => PlaceReply::Error(e.body()),
+            }
+        };
+        ctx.spawn(fut.into_actor(self).map(|res, _act, ctx| reply(ctx, &res)));
+    }
+}
+
+fn reply(ctx: &mut ws::WebsocketContext<VpListener<'_>>, res: &PlaceReply) {
+    if let Ok(res) = serde_json::to_string(res) {
+        ctx.text(res);
+    }
+}
 impl Handler<VpConnect<'_>> for VpSrv<'_> {
     type Result = ();
 
     fn handle(&mut self, msg: VpConnect, _ctx: &mut Self::Context) -> Self::Result {
         self.listeners.insert(msg.0.clone());
-        log::debug!(
+        if let Ok(res) = serde_json::to_string(&VpEvent::Presence(self.presence)) {
+            msg.0.do_send(VpRes(Cow::from(res)));
+        }
+        self.record_metrics();
+        tracing::debug!(
             "New client connection.Total connection count : {}",
             self.listeners.len()
         );
@@ -149,23 +732,114 @@ impl Handler<VpDisconnect<'_>> for VpSrv<'_> {
 
     fn handle(&mut self, msg: VpDisconnect, _ctx: &mut Self::Context) -> Self::Result {
         self.listeners.remove(&msg.0);
-        log::debug!(
+        self.record_metrics();
+        tracing::debug!(
             "Client Disconnected.Total connection count : {}",
             self.listeners.len()
         );
     }
 }
 
+impl Handler<VpSseConnect> for VpSrv<'_> {
+    type Result = ();
+
+    fn handle(&mut self, msg: VpSseConnect, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(last_id) = msg.last_id {
+            // updates missing from backlog are lost, client should refetch canvas : )
+            if self.missed(last_id) {
+                let _ = msg
+                    .tx
+                    .try_send(web::Bytes::from_static(b"event: resync\ndata: {}\n\n"));
+            }
+            self.backlog
+                .iter()
+                .filter(|update| update.version > last_id)
+                .filter_map(|update| VpEvent::Place(update.clone()).sse())
+                .for_each(|frame| {
+                    let _ = msg.tx.try_send(frame);
+                });
+        }
+        self.sse_listeners.push(msg.tx);
+        self.record_metrics();
+        tracing::debug!(
+            "New SSE connection.Total SSE connection count : {}",
+            self.sse_listeners.len()
+        );
+    }
+}
+
 impl Handler<PlaceUpdate> for VpSrv<'_> {
     type Result = ();
 
     fn handle(&mut self, msg: PlaceUpdate, _ctx: &mut Self::Context) -> Self::Result {
-        if let Ok(res) = serde_json::to_string(&msg) {
-            let msg = Cow::from(res);
-            self.listeners
-                .iter()
-                .for_each(|addr| addr.do_send(VpRes(msg.clone())));
+        // relayed after a reset , pixel isn't on current canvas
+        if msg.generation < self.generation {
+            tracing::debug!(
+                version = msg.version,
+                generation = msg.generation,
+                "dropped update of ended generation"
+            );
+            return;
+        }
+        self.generation = msg.generation;
+        self.latest = self.latest.max(msg.version);
+        self.cache.apply(&msg);
+        let _span = tracing::info_span!(
+            "vp_srv.broadcast",
+            version = msg.version,
+            listeners = self.listeners.len()
+        )
+        .entered();
+        self.broadcast(&VpEvent::Place(msg.clone()));
+        if self.backlog.len() == UPDATE_BACKLOG {
+            self.backlog.pop_front();
         }
+        self.backlog.push_back(msg);
+    }
+}
+
+impl Handler<Presence> for VpSrv<'_> {
+    type Result = ();
+
+    fn handle(&mut self, msg: Presence, _ctx: &mut Self::Context) -> Self::Result {
+        self.presence = msg;
+        self.broadcast(&VpEvent::Presence(msg));
+    }
+}
+
+impl Handler<SysEvent> for VpSrv<'_> {
+    type Result = ();
+
+    fn handle(&mut self, msg: SysEvent, _ctx: &mut Self::Context) -> Self::Result {
+        if let SysEvent::CanvasReset {
+            generation,
+            version,
+        } = msg
+        {
+            self.cache.invalidate();
+            // updates of ended generation
+            self.backlog.clear();
+            self.reset_version = self.reset_version.max(version);
+            self.generation = self.generation.max(generation);
+            self.latest = self.latest.max(version);
+        }
+        self.broadcast(&VpEvent::System { event: msg });
+    }
+}
+
+impl Handler<VpCount> for VpSrv<'_> {
+    type Result = usize;
+
+    fn handle(&mut self, _msg: VpCount, _ctx: &mut Self::Context) -> Self::Result {
+        self.listeners.len() + self.sse_listeners.len()
+    }
+}
+
+impl Handler<GetPresence> for VpSrv<'_> {
+    type Result = Presence;
+
+    fn handle(&mut self, _msg: GetPresence, _ctx: &mut Self::Context) -> Self::Result {
+        self.presence
     }
 }
 
@@ -173,6 +847,132 @@ impl Handler<VpRes<'_>> for VpListener<'_> {
     type Result = ();
 
     fn handle(&mut self, msg: VpRes, ctx: &mut Self::Context) -> Self::Result {
+        // updates sent before previous ping are still unread by client
+        let pending = self.overdue.saturating_sub(self.acked);
+        METRICS.ws_lag.observe(pending as f64);
+        if pending > self.conf.max_buffer {
+            tracing::debug!("Evicting slow client, {} updates pending", pending);
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("outbound buffer limit exceeded".to_string()),
+            }));
+            ctx.stop();
+            return;
+        }
+        self.sent += 1;
         ctx.text(msg.0.as_ref());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::header::{
+        HeaderName, ACCEPT, AUTHORIZATION, CONTENT_TYPE, IF_NONE_MATCH, WWW_AUTHENTICATE,
+    };
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+
+    use crate::models::cooldown_models::CooldownPolicy;
+    use crate::models::p_models::WsConfig;
+    use crate::models::validation_models::UnameRules;
+
+    use super::*;
+
+    fn with_header(name: HeaderName, value: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((name, value))
+            .to_http_request()
+    }
+
+    #[test]
+    fn json_unless_octet_stream_preferred() {
+        let format = |accept: &str| accepted_format(&with_header(ACCEPT, accept));
+        assert_eq!(
+            accepted_format(&TestRequest::default().to_http_request()),
+            CanvasFormat::Json
+        );
+        assert_eq!(format("application/json"), CanvasFormat::Json);
+        assert_eq!(format("*/*"), CanvasFormat::Json);
+        assert_eq!(format("application/octet-stream"), CanvasFormat::Raw);
+        assert_eq!(
+            format("application/json;q=0.5, application/octet-stream"),
+            CanvasFormat::Raw
+        );
+        assert_eq!(
+            format("application/json, application/octet-stream;q=0.9"),
+            CanvasFormat::Json
+        );
+    }
+
+    #[test]
+    fn png_preferred() {
+        let req = with_header(ACCEPT, "image/png, application/octet-stream;q=0.5");
+        assert!(prefers(&req, ContentType::png()));
+        assert!(!prefers(&req, ContentType::octet_stream()));
+    }
+
+    #[test]
+    fn etag_per_format_version_and_cooldown() {
+        let etag = canvas_etag(CanvasFormat::Json, 12, 30);
+        assert!(etag.weak);
+        assert_eq!(etag.to_string(), r#"W/"12-30""#);
+        assert_eq!(
+            canvas_etag(CanvasFormat::Raw, 12, 30).to_string(),
+            r#"W/"12-30-raw""#
+        );
+    }
+
+    #[test]
+    fn not_modified_on_matching_etag() {
+        let etag = canvas_etag(CanvasFormat::Json, 12, 30);
+        let modified = |tags: &str| !not_modified(&with_header(IF_NONE_MATCH, tags), &etag);
+        assert!(!modified(r#"W/"12-30""#));
+        // weak comparison , strong tag from a proxy still matches
+        assert!(!modified(r#""12-30""#));
+        assert!(!modified(r#"W/"11-30", W/"12-30""#));
+        assert!(!modified("*"));
+        assert!(modified(r#"W/"11-30""#));
+        assert!(modified(r#"W/"12-30-raw""#));
+        assert!(modified(r#"W/"12-60""#));
+        assert!(!not_modified(
+            &TestRequest::default().to_http_request(),
+            &etag
+        ));
+    }
+
+    fn app_state() -> AppState<'static> {
+        AppState::new(
+            "secret".into(),
+            "canvas".into(),
+            8,
+            30,
+            CooldownPolicy::new("", "", 0, 1, 0).unwrap(),
+            WsConfig::new(Duration::from_secs(5), Duration::from_secs(10), 0).unwrap(),
+            UnameRules::new(3, 12, ""),
+        )
+    }
+
+    #[test]
+    fn admin_token_required() {
+        let app_state = app_state();
+        let auth = |value: &str| authorize(&with_header(AUTHORIZATION, value), &app_state);
+        assert!(auth("Bearer secret").is_ok());
+        assert!(matches!(auth("Bearer wrong"), Err(VpError::Unauthorized)));
+        assert!(matches!(auth("Basic c2VjcmV0"), Err(VpError::Unauthorized)));
+        assert!(matches!(
+            authorize(&TestRequest::default().to_http_request(), &app_state),
+            Err(VpError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn unauthorized_is_json() {
+        let res = VpError::Unauthorized.error_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/json");
+    }
+}
@@ -1,87 +1,281 @@
 mod handlers;
+mod middlewares;
 mod models;
 mod services;
+mod telemetry;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix::Actor;
 use actix_cors::Cors;
-use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
 use dotenvy::dotenv;
 use handlers::p_handlers::get_canvas;
 use mimalloc::MiMalloc;
+use tracing_actix_web::TracingLogger;
 
 use crate::handlers::p_handlers::{
-    admin_update_pixel, pixel_info, reset_canvas, update_pixel, vplace,
+    admin_broadcast, admin_canvas_state, admin_cooldown, admin_moderation, admin_shadowban,
+    admin_tier, admin_update_pixel, canvas_diff, canvas_generation, canvas_state, canvas_tile,
+    canvas_tiles, events, generation_pixel, get_challenge, healthz, invalid_request, metrics,
+    online_stats, pixel_info, readyz, reset_canvas, update_pixel, vplace,
 };
-use crate::models::p_models::{AppState, VpSrv};
+use crate::middlewares::metrics_middleware::HttpMetrics;
+use crate::models::cache_models::CanvasCache;
+use crate::models::challenge_models::ChallengeVerifier;
+use crate::models::cooldown_models::CooldownPolicy;
+use crate::models::migration_models::SchemaConfig;
+use crate::models::p_models::{AppState, VpSrv, WsConfig};
+use crate::models::raid_models::{RaidConfig, RaidDetector};
+use crate::models::rate_models::{IpNet, RateLimitConfig};
+use crate::models::redis_models::{RedisConfig, RedisManager};
 use crate::models::scylla_models::ScyllaBuilder;
-use crate::services::p_services::init_place;
+use crate::models::tile_models::TileConfig;
+use crate::models::validation_models::UnameRules;
+use crate::services::challenge_services::{MockCaptchaVerifier, NoChallenge, PowVerifier};
+use crate::services::p_services::{init_place, subscribe_place, track_presence, watch_lifecycle};
+use crate::telemetry::{init_tracing, shutdown_tracing};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    env::set_var("RUST_LOG", "actix_web=debug");
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
+    init_tracing();
     let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| "redis://0.0.0.0:6379".to_string());
     let scylla_url = env::var("SCYLLA_URL").unwrap_or_else(|_| "0.0.0.0:9042".to_string());
     let canvas_dim =
         env::var("CANVAS_DIM").map_or(500, |count| count.parse::<u32>().unwrap_or(500));
+    let schema = SchemaConfig::new(
+        &env::var("SCYLLA_KEYSPACE").unwrap_or_else(|_| "vplace".to_string()),
+        &env::var("SCYLLA_REPLICATION_CLASS")
+            .unwrap_or_else(|_| "NetworkTopologyStrategy".to_string()),
+        &env::var("SCYLLA_REPLICATION").unwrap_or_else(|_| "replication_factor=1".to_string()),
+    )
+    .expect("Invalid SCYLLA_KEYSPACE or SCYLLA_REPLICATION");
+    let scylla_builder = ScyllaBuilder::try_init(
+        &scylla_url,
+        env::var("SCYLLA_TILE_SIZE").map_or(32, |s| s.parse::<u32>().unwrap_or(32)),
+        schema,
+    )
+    .await
+    .expect("Error initiating ScyllaBuilder");
+    let command = env::args().nth(1);
+    // `v-place migrate` applies pending schema migrations and exits
+    // with --alter-replication it also applies SCYLLA_REPLICATION to an existing keyspace
+    if command.as_deref() == Some("migrate")
+        || env::var("SCYLLA_AUTO_MIGRATE").is_ok_and(|a| a.eq("true"))
+    {
+        let version = scylla_builder
+            .migrate(
+                command.as_deref() == Some("migrate")
+                    && env::args().any(|a| a.eq("--alter-replication")),
+            )
+            .await
+            .expect("Unable to migrate scylla schema");
+        tracing::info!("Scylla schema at version {}", version);
+        if command.as_deref() == Some("migrate") {
+            shutdown_tracing();
+            return Ok(());
+        }
+    }
+    let scylla_man = scylla_builder
+        .try_build()
+        .await
+        .unwrap_or_else(|e| panic!("Unable to Build ScyllaManger : {}", e));
+    // `v-place migrate-canvas` moves pixel data from the old 4 part canvas table and exits
+    if command.as_deref() == Some("migrate-canvas") {
+        scylla_man
+            .migrate_legacy_canvas()
+            .await
+            .expect("Unable to migrate legacy canvas");
+        shutdown_tracing();
+        return Ok(());
+    }
     let canvas_id = env::var("CANVAS_ID").unwrap_or_else(|_| "vplace_1".to_string());
     let cooldown = env::var("COOLDOWN").map_or(60, |c| c.parse::<usize>().unwrap_or(60));
+    let cooldown_policy = CooldownPolicy::new(
+        &env::var("COOLDOWN_TIERS").unwrap_or_default(),
+        &env::var("COOLDOWN_REGIONS").unwrap_or_default(),
+        env::var("COOLDOWN_LOAD_STEP").map_or(0, |s| s.parse::<u64>().unwrap_or(0)),
+        env::var("COOLDOWN_LOAD_MAX").map_or(4, |m| m.parse::<u64>().unwrap_or(4)),
+        env::var("PIXEL_CREDITS").map_or(0, |c| c.parse::<u64>().unwrap_or(0)),
+    )
+    .expect("Invalid COOLDOWN_TIERS or COOLDOWN_REGIONS");
+    let rate_conf = web::Data::new(RateLimitConfig {
+        ip_limit: env::var("RATE_LIMIT_IP").map_or(0, |l| l.parse::<u64>().unwrap_or(0)),
+        subnet_limit: env::var("RATE_LIMIT_SUBNET").map_or(0, |l| l.parse::<u64>().unwrap_or(0)),
+        window: env::var("RATE_LIMIT_WINDOW").map_or(60, |w| w.parse::<u64>().unwrap_or(60)),
+        v4_prefix: env::var("RATE_LIMIT_V4_PREFIX").map_or(24, |p| p.parse::<u8>().unwrap_or(24)),
+        v6_prefix: env::var("RATE_LIMIT_V6_PREFIX").map_or(64, |p| p.parse::<u8>().unwrap_or(64)),
+        trusted_proxies: env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(IpNet::parse)
+            .collect(),
+    });
+    let verifier: Arc<dyn ChallengeVerifier> = match env::var("CHALLENGE").as_deref() {
+        Ok("pow") => Arc::new(PowVerifier {
+            difficulty: env::var("CHALLENGE_DIFFICULTY")
+                .map_or(20, |d| d.parse::<u32>().unwrap_or(20)),
+            ttl: env::var("CHALLENGE_TTL").map_or(300, |t| t.parse::<usize>().unwrap_or(300)),
+        }),
+        Ok("mock") => Arc::new(MockCaptchaVerifier {
+            token: env::var("CHALLENGE_MOCK_TOKEN")
+                .expect("Env Var CHALLENGE_MOCK_TOKEN not found"),
+        }),
+        _ => Arc::new(NoChallenge),
+    };
+    let verifier = web::Data::from(verifier);
+    let detector = web::Data::new(RaidDetector::new(RaidConfig {
+        ip_uids: env::var("RAID_IP_UIDS").map_or(0, |n| n.parse::<usize>().unwrap_or(0)),
+        ip_window: env::var("RAID_IP_WINDOW").map_or(600, |w| w.parse::<i64>().unwrap_or(600)),
+        new_age: env::var("RAID_NEW_AGE").map_or(86400, |a| a.parse::<i64>().unwrap_or(86400)),
+        periodic_samples: env::var("RAID_PERIODIC_SAMPLES")
+            .map_or(0, |n| n.parse::<usize>().unwrap_or(0)),
+        periodic_jitter_ms: env::var("RAID_PERIODIC_JITTER_MS")
+            .map_or(50, |j| j.parse::<i64>().unwrap_or(50)),
+        region_size: env::var("RAID_REGION_SIZE").map_or(16, |s| s.parse::<u32>().unwrap_or(16)),
+        region_accounts: env::var("RAID_REGION_ACCOUNTS")
+            .map_or(0, |n| n.parse::<usize>().unwrap_or(0)),
+        region_window: env::var("RAID_REGION_WINDOW")
+            .map_or(60, |w| w.parse::<i64>().unwrap_or(60)),
+        auto_shadowban: env::var("AUTO_SHADOWBAN").is_ok_and(|a| a.eq("true")),
+    }));
+    let ws_conf = WsConfig::new(
+        // zero interval would panic
+        Duration::from_secs(
+            env::var("WS_HEARTBEAT").map_or(5, |h| h.parse::<u64>().unwrap_or(5).max(1)),
+        ),
+        Duration::from_secs(env::var("WS_TIMEOUT").map_or(15, |t| t.parse::<u64>().unwrap_or(15))),
+        env::var("WS_MAX_BUFFER").map_or(512, |b| b.parse::<u64>().unwrap_or(512)),
+    )
+    .unwrap_or_else(|e| panic!("Invalid websocket config : {}", e));
+    let presence_interval = Duration::from_secs(
+        env::var("PRESENCE_INTERVAL").map_or(10, |i| i.parse::<u64>().unwrap_or(10).max(1)),
+    );
+    let presence_window =
+        env::var("PRESENCE_WINDOW").map_or(300, |w| w.parse::<i64>().unwrap_or(300));
     let admin_token = env::var("ADMIN_TOKEN").expect("Env Var ADMIN_TOKEN not found");
     let host_port = format!("{}:{}", host, port);
     let redis_client = redis::Client::open(redis_url).expect("Error connecting to RedisDB");
-    let redis = web::Data::new(redis_client);
-    let scylla_man = ScyllaBuilder::try_init(&scylla_url, canvas_dim)
-        .await
-        .expect("Error initiating ScyllaBuilder")
-        .try_build()
+    let redis_conf = RedisConfig {
+        retries: env::var("REDIS_RETRIES").map_or(6, |r| r.parse::<usize>().unwrap_or(6)),
+        backoff_base: env::var("REDIS_BACKOFF_BASE").map_or(2, |b| b.parse::<u64>().unwrap_or(2)),
+        backoff_factor: env::var("REDIS_BACKOFF_FACTOR")
+            .map_or(100, |f| f.parse::<u64>().unwrap_or(100)),
+        connect_timeout: Duration::from_millis(
+            env::var("REDIS_CONNECT_TIMEOUT_MS").map_or(5000, |t| t.parse::<u64>().unwrap_or(5000)),
+        ),
+        response_timeout: Duration::from_millis(
+            env::var("REDIS_RESPONSE_TIMEOUT_MS")
+                .map_or(1000, |t| t.parse::<u64>().unwrap_or(1000)),
+        ),
+    };
+    let redis_man = RedisManager::connect(redis_client.clone(), &redis_conf)
         .await
-        .expect("Unable to Build ScyllaManger");
+        .expect("Error connecting to RedisDB");
+    let redis = web::Data::new(redis_man.clone());
     let scylla = web::Data::new(scylla_man);
+    let uname_rules = UnameRules::new(
+        env::var("UNAME_MIN_LEN").map_or(3, |l| l.parse::<usize>().unwrap_or(3)),
+        env::var("UNAME_MAX_LEN").map_or(20, |l| l.parse::<usize>().unwrap_or(20)),
+        &env::var("UNAME_BLOCKLIST").unwrap_or_default(),
+    );
     let app_state = web::Data::new(AppState::new(
         admin_token.into(),
         canvas_id.into(),
         canvas_dim,
         cooldown,
+        cooldown_policy,
+        ws_conf,
+        uname_rules,
     ));
-    let vp_srv = VpSrv::new().start();
+    let tiles = TileConfig::new(
+        env::var("TILE_SIZE").map_or(64, |s| s.parse::<u32>().unwrap_or(64)),
+        &env::var("PALETTE").unwrap_or_default(),
+    );
+    let cache = web::Data::new(CanvasCache::new(canvas_dim, tiles));
+    let vp_srv = VpSrv::new(cache.clone()).start();
     init_place(&app_state, &redis)
         .await
         .expect("Error Initialising Canvas");
-    log::debug!("Canvas {} Initialised.", app_state.canvas_id);
-    log::debug!(
+    tracing::debug!("Canvas {} Initialised.", app_state.canvas_id);
+    actix_web::rt::spawn(subscribe_place(
+        redis_client,
+        app_state.update_channel(),
+        app_state.instance_id,
+        vp_srv.clone(),
+        cache.clone(),
+    ));
+    actix_web::rt::spawn(watch_lifecycle(
+        app_state.clone(),
+        redis_man.clone(),
+        vp_srv.clone(),
+    ));
+    actix_web::rt::spawn(track_presence(
+        app_state.clone(),
+        redis_man,
+        vp_srv.clone(),
+        presence_interval,
+        presence_window,
+    ));
+    tracing::debug!(
         "Canvas Dimension : {}x{}",
         app_state.canvas_dim,
         app_state.canvas_dim
     );
-    log::info!("v-place server listening on : {}", host_port);
+    tracing::info!("v-place server listening on : {}", host_port);
     let cpus = num_cpus::get();
     HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(HttpMetrics)
+            .wrap(TracingLogger::default())
             // use only in testing : )
             .wrap(Cors::permissive())
+            .app_data(web::JsonConfig::default().error_handler(invalid_request))
+            .app_data(web::QueryConfig::default().error_handler(invalid_request))
+            .app_data(web::PathConfig::default().error_handler(invalid_request))
             .app_data(app_state.clone())
             .app_data(web::Data::new(vp_srv.clone()))
             .app_data(redis.clone())
             .app_data(scylla.clone())
+            .app_data(rate_conf.clone())
+            .app_data(verifier.clone())
+            .app_data(detector.clone())
+            .app_data(cache.clone())
             .service(reset_canvas)
             .service(vplace)
+            .service(events)
+            .service(online_stats)
             .service(get_canvas)
+            .service(canvas_state)
+            .service(canvas_diff)
+            .service(canvas_generation)
+            .service(generation_pixel)
+            .service(canvas_tiles)
+            .service(canvas_tile)
+            .service(get_challenge)
             .service(update_pixel)
             .service(admin_update_pixel)
+            .service(admin_broadcast)
+            .service(admin_canvas_state)
+            .service(admin_cooldown)
+            .service(admin_tier)
+            .service(admin_moderation)
+            .service(admin_shadowban)
             .service(pixel_info)
+            .service(metrics)
+            .service(healthz)
+            .service(readyz)
     })
     .bind(host_port)?
     .workers(cpus * 2)
     .run()
-    .await
+    .await?;
+    shutdown_tracing();
+    Ok(())
 }
@@ -0,0 +1,2 @@
+pub mod metrics_middleware;
+pub mod rate_middleware;
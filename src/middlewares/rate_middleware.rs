@@ -0,0 +1,73 @@
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{web, Error, HttpResponse};
+use futures::future::{ready, LocalBoxFuture, Ready};
+
+use crate::models::p_models::AppState;
+use crate::models::rate_models::{RateLimitConfig, RateLimited};
+use crate::models::redis_models::RedisManager;
+use crate::services::rate_services::rate_limited;
+
+// Per ip and per subnet rate limiter
+// usage : #[post("/path", wrap = "IpRateLimit")]
+pub struct IpRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for IpRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = IpRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(IpRateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct IpRateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for IpRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Some(retry_after) = check_req(&req).await {
+                let res = HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, retry_after))
+                    .json(RateLimited { retry_after });
+                return Ok(req.into_response(res).map_into_right_body());
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+async fn check_req(req: &ServiceRequest) -> Option<u64> {
+    let conf = req.app_data::<web::Data<RateLimitConfig>>()?;
+    let app_state = req.app_data::<web::Data<AppState<'static>>>()?;
+    let redis = req.app_data::<web::Data<RedisManager>>()?;
+    rate_limited(conf.client_ip(req.request()), conf, app_state, redis).await
+}
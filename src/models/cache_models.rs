@@ -0,0 +1,534 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Bytes;
+use base64::engine::general_purpose;
+use base64::Engine;
+use serde::Serialize;
+
+use super::err_models::VpError;
+use super::p_models::{PlaceUpdate, UPDATE_BACKLOG};
+use super::tile_models::{Tile, TileConfig};
+
+// max. time `version` may stall behind applied updates , the missing one is lost then
+const GAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// In-memory copy of canvas bitfield, kept in sync by local and relayed pixel updates
+// cold (empty) until first read, which loads it from redis : )
+pub struct CanvasCache {
+    dim: u32,
+    tiles: TileConfig,
+    state: RwLock<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    canvas: Option<CachedCanvas>,
+    // updates received while cold, replayed over canvas loaded from redis
+    pending: VecDeque<PlaceUpdate>,
+    // highest version dropped from pending
+    dropped: u64,
+}
+
+struct CachedCanvas {
+    bytes: Vec<u8>,
+    // every version up to this one is applied , etags and diffs use it
+    version: u64,
+    // versions applied ahead of a missing one , merged into `version` once the gap closes
+    ahead: BTreeSet<u64>,
+    // since when `version` hasn't advanced while updates wait in `ahead`
+    gap_since: Option<Instant>,
+    // version loaded from redis, pixel changes before it are unknown
+    loaded: u64,
+    // generation of canvas
+    generation: u64,
+    // version of last update of each pixel, updates may arrive out of order
+    pixel_versions: Vec<u64>,
+    // version of last update within each tile
+    tile_versions: Vec<u64>,
+    // raw and base64 bodies, dropped on update and built again on next read
+    body: Option<(Bytes, Arc<str>)>,
+}
+impl CachedCanvas {
+    // a missing update never arrived , etags would stay on `version` forever
+    fn stalled(&self) -> bool {
+        self.gap_since
+            .is_some_and(|since| since.elapsed() > GAP_TIMEOUT)
+    }
+    // false if update is outside of canvas or of a newer generation
+    fn apply(&mut self, update: &PlaceUpdate, dim: u32, tiles: &TileConfig) -> bool {
+        // ended generation
+        if update.generation < self.generation {
+            return true;
+        }
+        if update.generation > self.generation {
+            return false;
+        }
+        let Ok(offset) = usize::try_from(update.loc.0 * dim + update.loc.1) else {
+            return false;
+        };
+        let (Some(pixel_version), Some(byte)) = (
+            self.pixel_versions.get_mut(offset),
+            self.bytes.get_mut(offset / 2),
+        ) else {
+            return false;
+        };
+        // already applied
+        if update.version <= self.version || !self.ahead.insert(update.version) {
+            return true;
+        }
+        let version = self.version;
+        while self.ahead.remove(&(self.version + 1)) {
+            self.version += 1;
+        }
+        if self.ahead.is_empty() {
+            self.gap_since = None;
+        } else if self.version > version || self.gap_since.is_none() {
+            self.gap_since = Some(Instant::now());
+        }
+        // stale update of a pixel changed since : )
+        if update.version <= *pixel_version {
+            return true;
+        }
+        *pixel_version = update.version;
+        // u4 bitfield , even offsets are the high nibble
+        *byte = if offset % 2 == 0 {
+            (*byte & 0x0F) | (update.color << 4)
+        } else {
+            (*byte & 0xF0) | (update.color & 0x0F)
+        };
+        if let Some(tile_version) = self.tile_versions.get_mut(tiles.index(dim, update.loc)) {
+            *tile_version = (*tile_version).max(update.version);
+        }
+        self.body = None;
+        true
+    }
+}
+
+// bytes per pixel in raw diff : x(u32 be) y(u32 be) color(u8)
+const DIFF_PIXEL_SIZE: usize = 9;
+
+#[derive(Serialize)]
+pub struct CanvasDiff {
+    pub since: u64,
+    pub version: u64,
+    pub pixels: Vec<PlaceUpdate>,
+}
+impl CanvasDiff {
+    pub fn raw(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(self.pixels.len() * DIFF_PIXEL_SIZE);
+        for pixel in &self.pixels {
+            raw.extend_from_slice(&pixel.loc.0.to_be_bytes());
+            raw.extend_from_slice(&pixel.loc.1.to_be_bytes());
+            raw.push(pixel.color);
+        }
+        raw
+    }
+}
+
+pub struct CanvasSnapshot {
+    pub version: u64,
+    pub raw: Bytes,
+    pub encoded: Arc<str>,
+}
+impl CanvasSnapshot {
+    pub fn new(bytes: Vec<u8>, version: u64) -> Self {
+        let encoded = general_purpose::STANDARD_NO_PAD.encode(&bytes).into();
+        Self {
+            version,
+            raw: Bytes::from(bytes),
+            encoded,
+        }
+    }
+}
+
+impl CanvasCache {
+    pub fn new(dim: u32, tiles: TileConfig) -> Self {
+        Self {
+            dim,
+            tiles,
+            state: RwLock::new(CacheState::default()),
+        }
+    }
+    pub fn snapshot(&self) -> Option<CanvasSnapshot> {
+        if let Ok(state) = self.state.read() {
+            let canvas = state.canvas.as_ref().filter(|c| !c.stalled())?;
+            if let Some((raw, encoded)) = &canvas.body {
+                return Some(CanvasSnapshot {
+                    version: canvas.version,
+                    raw: raw.clone(),
+                    encoded: encoded.clone(),
+                });
+            }
+        }
+        let mut state = self.state.write().ok()?;
+        let canvas = state.canvas.as_mut().filter(|c| !c.stalled())?;
+        let (raw, encoded) = canvas
+            .body
+            .get_or_insert_with(|| {
+                let encoded = general_purpose::STANDARD_NO_PAD
+                    .encode(&canvas.bytes)
+                    .into();
+                (Bytes::copy_from_slice(&canvas.bytes), encoded)
+            })
+            .clone();
+        Some(CanvasSnapshot {
+            version: canvas.version,
+            raw,
+            encoded,
+        })
+    }
+    // store canvas of `generation` fetched from redis at `version`
+    pub fn load(&self, bytes: Vec<u8>, version: u64, generation: u64) {
+        let Ok(mut state) = self.state.write() else {
+            return;
+        };
+        // a newer canvas is already cached, or updates after `version` were lost
+        if state
+            .canvas
+            .as_ref()
+            .is_some_and(|c| c.version > version && !c.stalled())
+            || state.dropped > version
+        {
+            return;
+        }
+        let pixels = usize::try_from(self.dim * self.dim).unwrap_or_default();
+        let tiles = self.tiles.count(self.dim) as usize;
+        let mut canvas = CachedCanvas {
+            bytes,
+            version,
+            ahead: BTreeSet::new(),
+            gap_since: None,
+            loaded: version,
+            generation,
+            pixel_versions: vec![version; pixels],
+            tile_versions: vec![version; tiles.pow(2)],
+            body: None,
+        };
+        let state = &mut *state;
+        for update in state.pending.drain(..) {
+            if !canvas.apply(&update, self.dim, &self.tiles) {
+                // canvas in redis doesn't match canvas dimension, or was reset since
+                return;
+            }
+        }
+        state.dropped = 0;
+        state.canvas = Some(canvas);
+    }
+    pub fn apply(&self, update: &PlaceUpdate) {
+        let Ok(mut state) = self.state.write() else {
+            return;
+        };
+        let state = &mut *state;
+        match state.canvas.as_mut() {
+            Some(canvas) => {
+                // outside of canvas, newer generation, or a missing update never arrived
+                if !canvas.apply(update, self.dim, &self.tiles)
+                    || canvas.ahead.len() > UPDATE_BACKLOG
+                    || canvas.stalled()
+                {
+                    state.canvas = None;
+                }
+            }
+            None => {
+                if state.pending.len() == UPDATE_BACKLOG {
+                    if let Some(dropped) = state.pending.pop_front() {
+                        state.dropped = state.dropped.max(dropped.version);
+                    }
+                }
+                state.pending.push_back(update.clone());
+            }
+        }
+    }
+    // pixels changed after version `since`, oldest first
+    // None when cold , Err when changes since `since` are not known or larger than the canvas
+    pub fn diff(&self, since: u64) -> Option<Result<CanvasDiff, VpError>> {
+        let state = self.state.read().ok()?;
+        let canvas = state.canvas.as_ref().filter(|c| !c.stalled())?;
+        if since < canvas.loaded {
+            return Some(Err(VpError::DiffExpired(since)));
+        }
+        let mut pixels: Vec<PlaceUpdate> = canvas
+            .pixel_versions
+            .iter()
+            .enumerate()
+            .filter(|(_, version)| **version > since)
+            .map(|(offset, version)| {
+                let byte = canvas.bytes[offset / 2];
+                let offset = offset as u32;
+                PlaceUpdate {
+                    loc: (offset / self.dim, offset % self.dim),
+                    color: if offset.is_multiple_of(2) {
+                        byte >> 4
+                    } else {
+                        byte & 0x0F
+                    },
+                    version: *version,
+                    generation: canvas.generation,
+                }
+            })
+            .collect();
+        // full canvas is cheaper : )
+        if pixels.len() * DIFF_PIXEL_SIZE > canvas.bytes.len() {
+            return Some(Err(VpError::DiffExpired(since)));
+        }
+        pixels.sort_unstable_by_key(|p| p.version);
+        Some(Ok(CanvasDiff {
+            since,
+            version: canvas.version.max(since),
+            pixels,
+        }))
+    }
+    // version of cached canvas , None when cold
+    pub fn version(&self) -> Option<u64> {
+        let state = self.state.read().ok()?;
+        Some(state.canvas.as_ref().filter(|c| !c.stalled())?.version)
+    }
+    pub fn tile_config(&self) -> &TileConfig {
+        &self.tiles
+    }
+    pub fn tile(&self, tx: u32, ty: u32) -> Option<Tile> {
+        let state = self.state.read().ok()?;
+        let canvas = state.canvas.as_ref().filter(|c| !c.stalled())?;
+        let version = *canvas
+            .tile_versions
+            .get(tx as usize * self.tiles.count(self.dim) as usize + ty as usize)?;
+        Tile::extract(&canvas.bytes, self.dim, &self.tiles, (tx, ty), version)
+    }
+    // (canvas version, tile versions)
+    pub fn tile_versions(&self) -> Option<(u64, Vec<u64>)> {
+        let state = self.state.read().ok()?;
+        let canvas = state.canvas.as_ref().filter(|c| !c.stalled())?;
+        Some((canvas.version, canvas.tile_versions.clone()))
+    }
+    // drop cached canvas, next read loads it from redis
+    pub fn invalidate(&self) {
+        if let Ok(mut state) = self.state.write() {
+            *state = CacheState::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8x8 canvas of 4x4 tiles , diffs up to 3 pixels
+    const DIM: u32 = 8;
+
+    fn cache() -> CanvasCache {
+        CanvasCache::new(DIM, TileConfig::new(4, ""))
+    }
+
+    fn blank() -> Vec<u8> {
+        vec![0; (DIM * DIM / 2) as usize]
+    }
+
+    fn update(loc: (u32, u32), color: u8, version: u64) -> PlaceUpdate {
+        PlaceUpdate {
+            loc,
+            color,
+            version,
+            generation: 1,
+        }
+    }
+
+    fn pixels(diff: CanvasDiff) -> Vec<((u32, u32), u8, u64)> {
+        diff.pixels
+            .iter()
+            .map(|p| (p.loc, p.color, p.version))
+            .collect()
+    }
+
+    #[test]
+    fn cold_until_loaded() {
+        let cache = cache();
+        assert!(cache.snapshot().is_none());
+        assert!(cache.diff(0).is_none());
+        assert!(cache.tile(0, 0).is_none());
+        cache.load(blank(), 7, 1);
+        assert_eq!(cache.snapshot().unwrap().version, 7);
+        cache.invalidate();
+        assert!(cache.snapshot().is_none());
+    }
+
+    #[test]
+    fn apply_updates_bytes_and_version() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((0, 0), 0xA, 11));
+        cache.apply(&update((0, 1), 0x5, 12));
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!(snapshot.version, 12);
+        assert_eq!(snapshot.raw[0], 0xA5);
+        assert_eq!(
+            pixels(cache.diff(11).unwrap().unwrap()),
+            vec![((0, 1), 0x5, 12)]
+        );
+        assert_eq!(cache.tile_versions().unwrap(), (12, vec![12, 10, 10, 10]));
+    }
+
+    #[test]
+    fn pending_replayed_over_load() {
+        let cache = cache();
+        cache.apply(&update((0, 0), 1, 11));
+        cache.apply(&update((7, 7), 2, 12));
+        // canvas read from redis already has version 11
+        cache.load(blank(), 11, 1);
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!(snapshot.version, 12);
+        assert_eq!(snapshot.raw[0], 0);
+        assert_eq!(snapshot.raw[31], 0x02);
+    }
+
+    #[test]
+    fn load_rejected_after_dropped_pending() {
+        let cache = cache();
+        for version in 1..=UPDATE_BACKLOG as u64 + 1 {
+            cache.apply(&update((0, 0), 1, version));
+        }
+        // update 1 was dropped before it could be replayed
+        cache.load(blank(), 0, 1);
+        assert!(cache.snapshot().is_none());
+        cache.load(blank(), 1, 1);
+        assert_eq!(cache.snapshot().unwrap().version, UPDATE_BACKLOG as u64 + 1);
+    }
+
+    #[test]
+    fn version_waits_for_out_of_order_update() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((1, 0), 3, 12));
+        assert_eq!(cache.snapshot().unwrap().version, 10);
+        let diff = cache.diff(10).unwrap().unwrap();
+        assert_eq!(diff.version, 10);
+        assert_eq!(pixels(diff), vec![((1, 0), 3, 12)]);
+        cache.apply(&update((1, 1), 4, 11));
+        assert_eq!(cache.snapshot().unwrap().version, 12);
+        assert_eq!(
+            pixels(cache.diff(10).unwrap().unwrap()),
+            vec![((1, 1), 4, 11), ((1, 0), 3, 12)]
+        );
+    }
+
+    #[test]
+    fn stale_pixel_update_keeps_newer_color() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((0, 0), 2, 12));
+        cache.apply(&update((0, 0), 1, 11));
+        // duplicate
+        cache.apply(&update((0, 0), 1, 11));
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!(snapshot.version, 12);
+        assert_eq!(snapshot.raw[0], 0x20);
+    }
+
+    #[test]
+    fn missing_update_drops_cache() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        for version in 12..=UPDATE_BACKLOG as u64 + 12 {
+            cache.apply(&update((0, 0), 1, version));
+        }
+        assert!(cache.snapshot().is_none());
+    }
+
+    fn backdate_gap(cache: &CanvasCache) {
+        let mut state = cache.state.write().unwrap();
+        let canvas = state.canvas.as_mut().unwrap();
+        canvas.gap_since = canvas.gap_since.map(|since| since - GAP_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn stalled_gap_drops_cache() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((0, 0), 1, 12));
+        assert_eq!(cache.version(), Some(10));
+        backdate_gap(&cache);
+        assert!(cache.snapshot().is_none());
+        assert!(cache.version().is_none());
+        assert!(cache.tile_versions().is_none());
+        // canvas from redis replaces the stalled one
+        cache.load(blank(), 12, 1);
+        assert_eq!(cache.snapshot().unwrap().version, 12);
+    }
+
+    #[test]
+    fn closed_gap_keeps_cache() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((0, 0), 1, 12));
+        cache.apply(&update((0, 1), 1, 11));
+        backdate_gap(&cache);
+        assert_eq!(cache.snapshot().unwrap().version, 12);
+        // progress restarts the timeout
+        cache.apply(&update((0, 0), 2, 14));
+        backdate_gap(&cache);
+        cache.apply(&update((0, 0), 3, 13));
+        cache.apply(&update((0, 0), 3, 16));
+        assert_eq!(cache.version(), Some(14));
+    }
+
+    #[test]
+    fn diff_expired() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        assert!(matches!(cache.diff(9), Some(Err(VpError::DiffExpired(9)))));
+        // more changes than the canvas itself
+        for (version, offset) in (11..).zip(0..4) {
+            cache.apply(&update((offset / DIM, offset % DIM), 1, version));
+        }
+        assert!(matches!(
+            cache.diff(10),
+            Some(Err(VpError::DiffExpired(10)))
+        ));
+        assert!(cache.diff(11).unwrap().is_ok());
+    }
+
+    #[test]
+    fn diff_expired_after_invalidate() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((0, 0), 1, 11));
+        assert!(cache.diff(10).unwrap().is_ok());
+        cache.invalidate();
+        cache.load(blank(), 15, 1);
+        assert!(matches!(
+            cache.diff(11),
+            Some(Err(VpError::DiffExpired(11)))
+        ));
+        let diff = cache.diff(15).unwrap().unwrap();
+        assert_eq!((diff.since, diff.version), (15, 15));
+        assert!(diff.pixels.is_empty());
+    }
+
+    #[test]
+    fn updates_of_other_generations() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        // relayed late from before a reset
+        cache.apply(&PlaceUpdate {
+            generation: 0,
+            ..update((0, 0), 1, 11)
+        });
+        let snapshot = cache.snapshot().unwrap();
+        assert_eq!((snapshot.version, snapshot.raw[0]), (10, 0));
+        // reset not seen yet
+        cache.apply(&PlaceUpdate {
+            generation: 2,
+            ..update((0, 0), 1, 12)
+        });
+        assert!(cache.snapshot().is_none());
+    }
+
+    #[test]
+    fn out_of_canvas_update_drops_cache() {
+        let cache = cache();
+        cache.load(blank(), 10, 1);
+        cache.apply(&update((DIM, 0), 1, 11));
+        assert!(cache.snapshot().is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::err_models::VpError;
+use super::p_models::AppState;
+use super::redis_models::RedisManager;
+
+// Challenge issued to client , eg: proof-of-work puzzle or captcha site key
+#[derive(Serialize)]
+pub struct Challenge {
+    pub kind: &'static str,
+    pub challenge: String,
+    // leading zero bits required in proof-of-work hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u32>,
+}
+
+// Anti-bot challenge verifier
+// checked in the pixel update path of new or flagged users
+pub trait ChallengeVerifier: Send + Sync {
+    // false skips challenge checks entirely
+    fn enabled(&self) -> bool {
+        true
+    }
+    // None if client doesn't need a challenge from us to build a proof
+    fn issue<'a>(
+        &'a self,
+        app_state: &'a AppState<'_>,
+        redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<Option<Challenge>, VpError>>;
+    fn verify<'a>(
+        &'a self,
+        uid: &'a Uuid,
+        proof: &'a str,
+        app_state: &'a AppState<'_>,
+        redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<bool, VpError>>;
+}
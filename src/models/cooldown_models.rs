@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+// Canvas region with its own share of cooldown
+// (x0,y0) inclusive , (x1,y1) exclusive
+pub struct Region {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+    // percentage of base cooldown , eg: 200 doubles it
+    pub pct: usize,
+}
+impl Region {
+    // "x0,y0,x1,y1=pct"
+    fn parse(region: &str) -> Option<Self> {
+        let (rect, pct) = region.split_once('=')?;
+        let rect = rect
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .ok()?;
+        match rect[..] {
+            [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Some(Self {
+                x0,
+                y0,
+                x1,
+                y1,
+                pct: pct.trim().parse::<usize>().ok()?,
+            }),
+            _ => None,
+        }
+    }
+    pub fn contains(&self, loc: (u32, u32)) -> bool {
+        (self.x0..self.x1).contains(&loc.0) && (self.y0..self.y1).contains(&loc.1)
+    }
+}
+
+// Cooldown Policy
+// effective cooldown = base cooldown * region% * tier% * load factor
+// base cooldown is the runtime one set by admin
+pub struct CooldownPolicy {
+    // user tier -> percentage of cooldown , eg: verified=50 halves the cooldown
+    pub tiers: HashMap<String, usize>,
+    pub regions: Vec<Region>,
+    // placements/sec per extra cooldown step, 0 disables load based cooldown
+    pub load_step: u64,
+    // max. cooldown multiplier under load
+    pub load_max: u64,
+    // max. pixel credits a user can save up, 0 disables burst mode
+    // a credit is refilled every cooldown
+    pub max_credits: u64,
+}
+impl CooldownPolicy {
+    // tiers   : "verified=50,trusted=75"
+    // regions : "x0,y0,x1,y1=pct;..."
+    // Err names the first invalid tier or region
+    pub fn new(
+        tiers: &str,
+        regions: &str,
+        load_step: u64,
+        load_max: u64,
+        max_credits: u64,
+    ) -> Result<Self, String> {
+        let tiers = tiers
+            .split(',')
+            .filter(|tier| !tier.trim().is_empty())
+            .map(|tier| {
+                tier.split_once('=')
+                    .and_then(|(name, pct)| {
+                        Some((name.trim().to_string(), pct.trim().parse::<usize>().ok()?))
+                    })
+                    .filter(|(name, _)| !name.is_empty())
+                    .ok_or_else(|| format!("invalid cooldown tier `{}`", tier.trim()))
+            })
+            .collect::<Result<_, _>>()?;
+        let regions = regions
+            .split(';')
+            .filter(|region| !region.trim().is_empty())
+            .map(|region| {
+                Region::parse(region)
+                    .ok_or_else(|| format!("invalid cooldown region `{}`", region.trim()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            tiers,
+            regions,
+            load_step,
+            load_max: load_max.max(1),
+            max_credits,
+        })
+    }
+    pub fn apply(
+        &self,
+        base: usize,
+        loc: Option<(u32, u32)>,
+        tier: Option<&str>,
+        pps: u64,
+    ) -> usize {
+        let cooldown = loc
+            .and_then(|loc| self.regions.iter().find(|region| region.contains(loc)))
+            .map_or(base, |region| base.saturating_mul(region.pct) / 100);
+        let cooldown = tier
+            .and_then(|tier| self.tiers.get(tier))
+            .map_or(cooldown, |pct| cooldown.saturating_mul(*pct) / 100);
+        let factor = match self.load_step {
+            0 => 1,
+            step => (1 + pps / step).min(self.load_max),
+        };
+        cooldown.saturating_mul(usize::try_from(factor).unwrap_or(usize::MAX))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateCooldown {
+    pub cooldown: usize,
+}
+
+// tier : None removes user from tier
+#[derive(Deserialize)]
+pub struct UpdateTier {
+    pub uid: Uuid,
+    pub tier: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(regions: &str, load_step: u64) -> CooldownPolicy {
+        CooldownPolicy::new("verified=50,trusted=75", regions, load_step, 4, 0).unwrap()
+    }
+
+    #[test]
+    fn parse_policy() {
+        let policy = policy("0,0,10,10=200; 5,5,20,20=50", 0);
+        assert_eq!(policy.tiers.get("verified"), Some(&50));
+        assert_eq!(policy.regions.len(), 2);
+        assert!(CooldownPolicy::new("verified", "", 0, 0, 0).is_err());
+        assert!(CooldownPolicy::new("=50", "", 0, 0, 0).is_err());
+        assert!(CooldownPolicy::new("", "0,0,10=200", 0, 0, 0).is_err());
+        // empty rect
+        assert!(CooldownPolicy::new("", "10,0,10,10=200", 0, 0, 0).is_err());
+        assert!(CooldownPolicy::new("", "0,0,10,10=", 0, 0, 0).is_err());
+        assert_eq!(CooldownPolicy::new("", "", 0, 0, 0).unwrap().load_max, 1);
+    }
+
+    #[test]
+    fn tiers() {
+        let policy = policy("", 0);
+        assert_eq!(policy.apply(30, None, None, 0), 30);
+        assert_eq!(policy.apply(30, None, Some("verified"), 0), 15);
+        assert_eq!(policy.apply(30, None, Some("trusted"), 0), 22);
+        assert_eq!(policy.apply(30, None, Some("unknown"), 0), 30);
+    }
+
+    #[test]
+    fn regions_scale_runtime_base() {
+        let policy = policy("0,0,10,10=200; 5,5,20,20=50", 0);
+        assert_eq!(policy.apply(30, Some((1, 1)), None, 0), 60);
+        // admin changed base cooldown at runtime
+        assert_eq!(policy.apply(10, Some((1, 1)), None, 0), 20);
+        // first matching region wins , x1/y1 exclusive
+        assert_eq!(policy.apply(30, Some((5, 5)), None, 0), 60);
+        assert_eq!(policy.apply(30, Some((10, 10)), None, 0), 15);
+        assert_eq!(policy.apply(30, Some((20, 20)), None, 0), 30);
+        assert_eq!(policy.apply(30, Some((1, 1)), Some("verified"), 0), 30);
+    }
+
+    #[test]
+    fn load_factor() {
+        let policy = policy("0,0,10,10=200", 100);
+        assert_eq!(policy.apply(30, None, None, 99), 30);
+        assert_eq!(policy.apply(30, None, None, 100), 60);
+        assert_eq!(policy.apply(30, None, None, 250), 90);
+        // capped at load_max
+        assert_eq!(policy.apply(30, None, None, 10_000), 120);
+        assert_eq!(policy.apply(30, Some((1, 1)), Some("verified"), 100), 60);
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        let policy = policy("0,0,10,10=200", 1);
+        assert_eq!(policy.apply(usize::MAX, None, None, 0), usize::MAX);
+        assert_eq!(policy.apply(usize::MAX / 2, None, None, 10), usize::MAX);
+    }
+}
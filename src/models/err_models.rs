@@ -2,9 +2,19 @@ use std::error::Error;
 use std::fmt::Display;
 use std::num::TryFromIntError;
 
+use actix::MailboxError;
+use actix_web::http::header::WWW_AUTHENTICATE;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
 use redis::RedisError;
 use scylla::transport::errors::{NewSessionError, QueryError};
+use scylla::transport::iterator::NextRowError;
 use scylla::transport::query_result::FirstRowTypedError;
+use serde::Serialize;
+use serde_json::json;
+
+use super::p_models::CanvasState;
+use super::validation_models::FieldError;
 
 #[derive(Debug)]
 pub enum VpError {
@@ -15,9 +25,29 @@ pub enum VpError {
     InvalidUser,
     ScyllaQueryErr(QueryError),
     ScyllaTypeErr(FirstRowTypedError),
+    ScyllaRowErr(NextRowError),
     ScyllaSessionErr(NewSessionError),
     ParseIntErr(TryFromIntError),
     NoPixelData,
+    SerdeErr(serde_json::Error),
+    MailboxErr(MailboxError),
+    CanvasNotOpen(CanvasState),
+    ChallengeRequired,
+    InvalidProof,
+    // missing or wrong admin token
+    Unauthorized,
+    Validation(Vec<FieldError>),
+    PngErr(png::EncodingError),
+    DiffExpired(u64),
+    CanvasNotFound,
+    GenerationNotFound(u64),
+    TileNotFound(u32, u32),
+    // canvas was reset again and again while running a generation script
+    GenerationMoved,
+    // (current, required) schema version
+    SchemaOutdated(i32, i32),
+    // (stored, configured) scylla tile size
+    TileSizeMismatch(u32, u32),
 }
 impl Error for VpError {}
 
@@ -32,12 +62,33 @@ impl From<QueryError> for VpError {
     }
 }
 
+impl From<NextRowError> for VpError {
+    fn from(err: NextRowError) -> Self {
+        match err {
+            NextRowError::QueryError(err) => Self::ScyllaQueryErr(err),
+            err => Self::ScyllaRowErr(err),
+        }
+    }
+}
+
 impl From<TryFromIntError> for VpError {
     fn from(err: TryFromIntError) -> Self {
         Self::ParseIntErr(err)
     }
 }
 
+impl From<serde_json::Error> for VpError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::SerdeErr(err)
+    }
+}
+
+impl From<png::EncodingError> for VpError {
+    fn from(err: png::EncodingError) -> Self {
+        Self::PngErr(err)
+    }
+}
+
 impl From<NewSessionError> for VpError {
     fn from(err: NewSessionError) -> Self {
         Self::ScyllaSessionErr(err)
@@ -57,13 +108,161 @@ impl Display for VpError {
             InvalidUser => write!(f, "[Invalid User]: Invalid User Id"),
             ScyllaQueryErr(e) => write!(f, "[Scylla Query Error]: {}", e),
             ScyllaTypeErr(e) => write!(f, "[Scylla Row Type Error]: {}", e),
+            ScyllaRowErr(e) => write!(f, "[Scylla Row Error]: {}", e),
             ScyllaSessionErr(e) => write!(f, "Unable to start New Scylla Session : {}", e),
             ParseIntErr(e) => write!(f, "[Error parsing Int]: {}", e),
             CanvasSizeMismatch => {
                 write!(f, "[Canvas Size Mismatch]: Enter (x,y) < Canvas Dimension")
             }
             NoPixelData => write!(f, "No pixel data found"),
+            SerdeErr(e) => write!(f, "[Serde Error]: {}", e),
+            MailboxErr(e) => write!(f, "[VpSrv Mailbox Error]: {}", e),
+            CanvasNotOpen(state) => write!(f, "[Canvas Not Open]: canvas is {:?}", state),
+            ChallengeRequired => {
+                write!(f, "[Challenge Required]: solve a challenge and send proof")
+            }
+            InvalidProof => write!(f, "[Invalid Proof]: challenge proof rejected"),
+            Unauthorized => write!(f, "[Unauthorized]: missing or invalid bearer token"),
+            Validation(errs) => {
+                let fields: Vec<String> = errs
+                    .iter()
+                    .map(|e| format!("{} {}", e.field, e.message))
+                    .collect();
+                write!(f, "[Validation Error]: {}", fields.join(", "))
+            }
+            PngErr(e) => write!(f, "[Png Encoding Error]: {}", e),
+            SchemaOutdated(current, required) => write!(
+                f,
+                "[Schema Outdated]: scylla schema version {} , required {}. run `v-place migrate`",
+                current, required
+            ),
+            TileSizeMismatch(stored, configured) => write!(
+                f,
+                "[Tile Size Mismatch]: scylla pixels are stored in {} x {} tiles, SCYLLA_TILE_SIZE is {}",
+                stored, stored, configured
+            ),
+            CanvasNotFound => write!(f, "[Canvas Not Found]: no canvas with given id"),
+            GenerationNotFound(generation) => {
+                write!(
+                    f,
+                    "[Generation Not Found]: no canvas generation {}",
+                    generation
+                )
+            }
+            GenerationMoved => {
+                write!(f, "[Generation Moved]: canvas is being reset, try again")
+            }
+            TileNotFound(tx, ty) => {
+                write!(f, "[Tile Not Found]: no tile ({},{}) in canvas", tx, ty)
+            }
+            DiffExpired(since) => write!(
+                f,
+                "[Diff Expired]: changes since version {} unavailable, refetch canvas",
+                since
+            ),
+        }
+    }
+}
+impl VpError {
+    // stable machine readable error code
+    pub fn code(&self) -> &'static str {
+        use VpError::*;
+        match self {
+            InitCanvasErr => "init_canvas_failed",
+            RedisErr(_) => "redis_unavailable",
+            ColorSizeMismatch => "color_out_of_range",
+            CanvasSizeMismatch => "loc_out_of_bounds",
+            InvalidUser => "user_not_found",
+            ScyllaQueryErr(_) | ScyllaSessionErr(_) => "scylla_unavailable",
+            ScyllaTypeErr(_) | ScyllaRowErr(_) => "scylla_row_type",
+            ParseIntErr(_) => "int_conversion",
+            NoPixelData => "pixel_not_found",
+            SerdeErr(_) => "serde_error",
+            MailboxErr(_) => "server_unavailable",
+            CanvasNotOpen(_) => "canvas_not_open",
+            ChallengeRequired => "challenge_required",
+            InvalidProof => "invalid_proof",
+            Unauthorized => "unauthorized",
+            Validation(_) => "validation_failed",
+            PngErr(_) => "png_encoding",
+            DiffExpired(_) => "diff_expired",
+            CanvasNotFound => "canvas_not_found",
+            GenerationNotFound(_) => "generation_not_found",
+            TileNotFound(..) => "tile_not_found",
+            GenerationMoved => "generation_moved",
+            SchemaOutdated(..) => "schema_outdated",
+            TileSizeMismatch(..) => "tile_size_mismatch",
+        }
+    }
+    fn details(&self) -> Option<serde_json::Value> {
+        use VpError::*;
+        match self {
+            ColorSizeMismatch => Some(json!({ "min": 0, "max": 15 })),
+            CanvasNotOpen(state) => Some(json!({ "state": state })),
+            Validation(errs) => Some(json!({ "fields": errs })),
+            DiffExpired(since) => Some(json!({ "since": since })),
+            _ => None,
+        }
+    }
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+        }
+    }
+}
+
+// Json body of error responses
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+impl ErrorBody {
+    // malformed request body
+    pub fn invalid_request(err: &impl Display) -> Self {
+        Self {
+            code: "invalid_request",
+            message: err.to_string(),
+            details: None,
+        }
+    }
+}
+
+impl actix_web::ResponseError for VpError {
+    fn status_code(&self) -> StatusCode {
+        use VpError::*;
+        match self {
+            ColorSizeMismatch | CanvasSizeMismatch | Validation(_) => StatusCode::BAD_REQUEST,
+            InvalidUser
+            | NoPixelData
+            | CanvasNotFound
+            | GenerationNotFound(_)
+            | TileNotFound(..) => StatusCode::NOT_FOUND,
+            Unauthorized => StatusCode::UNAUTHORIZED,
+            ChallengeRequired | InvalidProof => StatusCode::FORBIDDEN,
+            CanvasNotOpen(_) | GenerationMoved => StatusCode::CONFLICT,
+            DiffExpired(_) => StatusCode::GONE,
+            RedisErr(_) | ScyllaQueryErr(_) | ScyllaSessionErr(_) | MailboxErr(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            InitCanvasErr | SchemaOutdated(..) | TileSizeMismatch(..) | ScyllaTypeErr(_)
+            | ScyllaRowErr(_) | ParseIntErr(_) | SerdeErr(_) | PngErr(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        if self.status_code().is_server_error() {
+            tracing::error!("{}", self);
+        }
+        let mut res = HttpResponse::build(self.status_code());
+        if let VpError::Unauthorized = self {
+            res.insert_header((WWW_AUTHENTICATE, "Bearer"));
         }
+        res.json(self.body())
     }
 }
-impl actix_web::ResponseError for VpError {}
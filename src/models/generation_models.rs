@@ -0,0 +1,34 @@
+use serde::Serialize;
+
+// A canvas generation , each reset ends one and starts the next
+pub struct Generation {
+    pub generation: u64,
+    // placements still go to current generation
+    pub current: bool,
+    pub started_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    // canvas version when generation ended
+    pub final_version: Option<u64>,
+    pub stats: GenerationStats,
+    pub canvas: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct GenerationStats {
+    pub placements: u64,
+    // approx. no. of distinct users who placed a pixel
+    pub painters: u64,
+}
+
+#[derive(Serialize)]
+pub struct GenerationResponse<'a> {
+    pub id: &'a str,
+    pub dim: u32,
+    pub generation: u64,
+    pub current: bool,
+    pub started_at: Option<i64>,
+    pub ended_at: Option<i64>,
+    pub final_version: Option<u64>,
+    pub stats: &'a GenerationStats,
+    pub canvas: &'a str,
+}
@@ -0,0 +1,27 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+// status of a single dependency checked by /readyz
+#[derive(Serialize)]
+pub struct DepStatus {
+    pub ok: bool,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    // dependency name -> status
+    pub checks: BTreeMap<&'static str, DepStatus>,
+}
+impl Readiness {
+    pub fn new(checks: BTreeMap<&'static str, DepStatus>) -> Self {
+        Self {
+            ready: checks.values().all(|check| check.ok),
+            checks,
+        }
+    }
+}
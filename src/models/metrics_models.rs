@@ -0,0 +1,135 @@
+use std::future::Future;
+
+use once_cell::sync::Lazy;
+use prometheus::{Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+
+// latency buckets (secs) for redis/scylla queries and http requests
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+// websocket client lag buckets (no. of unacknowledged updates)
+const LAG_BUCKETS: &[f64] = &[0.0, 1.0, 4.0, 16.0, 64.0, 256.0, 1024.0];
+
+// Prometheus metrics of this instance, exposed at /metrics
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    pub registry: Registry,
+    // placements by source : user, admin
+    pub placements: IntCounterVec,
+    // rejected placements by reason : cooldown, bounds, color ..
+    pub rejected: IntCounterVec,
+    pub ws_clients: IntGauge,
+    pub sse_clients: IntGauge,
+    // updates queued for sse clients
+    pub broadcast_queue: IntGauge,
+    pub ws_lag: Histogram,
+    pub redis_latency: HistogramVec,
+    pub scylla_latency: HistogramVec,
+    pub http_requests: IntCounterVec,
+    pub http_latency: HistogramVec,
+}
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("vplace".to_string()), None)
+            .expect("Unable to create metrics registry");
+        let placements =
+            IntCounterVec::new(Opts::new("placements_total", "pixels placed"), &["source"])
+                .expect("Invalid metric");
+        let rejected = IntCounterVec::new(
+            Opts::new("rejected_placements_total", "pixel updates rejected"),
+            &["reason"],
+        )
+        .expect("Invalid metric");
+        let ws_clients =
+            IntGauge::new("ws_clients", "connected websocket clients").expect("Invalid metric");
+        let sse_clients =
+            IntGauge::new("sse_clients", "connected sse clients").expect("Invalid metric");
+        let broadcast_queue = IntGauge::new(
+            "broadcast_queue_depth",
+            "updates queued for delivery to sse clients",
+        )
+        .expect("Invalid metric");
+        let ws_lag = Histogram::with_opts(
+            HistogramOpts::new(
+                "ws_client_lag",
+                "updates not yet acknowledged by a websocket client",
+            )
+            .buckets(LAG_BUCKETS.to_vec()),
+        )
+        .expect("Invalid metric");
+        let redis_latency = HistogramVec::new(
+            HistogramOpts::new("redis_seconds", "redis latency per operation")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["op"],
+        )
+        .expect("Invalid metric");
+        let scylla_latency = HistogramVec::new(
+            HistogramOpts::new("scylla_seconds", "scylla latency per operation")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["op"],
+        )
+        .expect("Invalid metric");
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "http requests per route"),
+            &["method", "route", "status"],
+        )
+        .expect("Invalid metric");
+        let http_latency = HistogramVec::new(
+            HistogramOpts::new("http_request_seconds", "http latency per route")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+            &["method", "route"],
+        )
+        .expect("Invalid metric");
+        for metric in [
+            Box::new(placements.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(rejected.clone()),
+            Box::new(ws_clients.clone()),
+            Box::new(sse_clients.clone()),
+            Box::new(broadcast_queue.clone()),
+            Box::new(ws_lag.clone()),
+            Box::new(redis_latency.clone()),
+            Box::new(scylla_latency.clone()),
+            Box::new(http_requests.clone()),
+            Box::new(http_latency.clone()),
+        ] {
+            registry
+                .register(metric)
+                .expect("Unable to register metric");
+        }
+        Self {
+            registry,
+            placements,
+            rejected,
+            ws_clients,
+            sse_clients,
+            broadcast_queue,
+            ws_lag,
+            redis_latency,
+            scylla_latency,
+            http_requests,
+            http_latency,
+        }
+    }
+    pub fn placed(&self, source: &str) {
+        self.placements.with_label_values(&[source]).inc();
+    }
+    pub fn reject(&self, reason: &str) {
+        self.rejected.with_label_values(&[reason]).inc();
+    }
+}
+
+// time a redis query
+pub async fn redis_timed<T>(op: &str, query: impl Future<Output = T>) -> T {
+    let _timer = METRICS.redis_latency.with_label_values(&[op]).start_timer();
+    query.await
+}
+
+// time a scylla query
+pub async fn scylla_timed<T>(op: &str, query: impl Future<Output = T>) -> T {
+    let _timer = METRICS
+        .scylla_latency
+        .with_label_values(&[op])
+        .start_timer();
+    query.await
+}
@@ -0,0 +1,142 @@
+// Versioned scylla schema , applied in order by `v-place migrate`
+// applied versions are recorded in {ks}.schema_version
+// statements should be idempotent (IF NOT EXISTS) , a failed migration is rerun as a whole : )
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    // {ks} is replaced by configured keyspace
+    pub statements: &'static [&'static str],
+}
+
+// append only , never edit a migration once released
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        statements: &[
+            //Store Pixel Update of Each User
+            //->used to check cooldown
+            //table to store User's last pixel placement
+            // settings fixed once data is stored , eg: tile_size
+            "CREATE TABLE IF NOT EXISTS {ks}.schema_config (name text,value text,PRIMARY KEY (name))",
+            "CREATE TABLE IF NOT EXISTS {ks}.player (id uuid,uname text,x int,y int,color int,last_placed timestamp,PRIMARY KEY (id))",
+            //Store All Pixel data
+            // UDT to store pixel_data
+            "CREATE TYPE IF NOT EXISTS {ks}.pixel_data (uname text,color int,last_placed timestamp)",
+            //table to store all pixel update data in canvas
+            // Divide the canvas into tile_size x tile_size tiles
+            //       ---------------------
+            //       | 0,0 | 0,1 | 0,2 |..
+            //       |-----|-----|-----|
+            //       | 1,0 | 1,1 | 1,2 |..
+            //       |-----|-----|-----|
+            //       |  .. |  .. |  .. |
+            // each tile (tx,ty) of a canvas generation is a partition with pixel details as rows
+            // of the form (x,y):pixel_data where pixel_data is UDT defined above : ) .
+            // pixels of past generations are kept
+            // replaces {ks}.canvas which split canvas into only 4 parts, see migrate_legacy_canvas
+            "CREATE TABLE IF NOT EXISTS {ks}.canvas_pixels (generation bigint,tx int,ty int,x int,y int,data frozen<pixel_data>,PRIMARY KEY ((generation,tx,ty),x,y))",
+        ],
+    },
+];
+
+pub fn latest_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+// keyspace and replication of scylla schema
+pub struct SchemaConfig {
+    pub keyspace: String,
+    // cql replication map , eg: {'class' : 'NetworkTopologyStrategy', 'replication_factor' : 1}
+    pub replication: String,
+}
+impl SchemaConfig {
+    // options : comma separated key=value , eg: "replication_factor=3" or "dc1=3,dc2=2"
+    // None if names aren't plain cql identifiers, they are spliced into queries
+    pub fn new(keyspace: &str, class: &str, options: &str) -> Option<Self> {
+        let ident = |s: &str| {
+            !s.is_empty()
+                && s.len() <= 48
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !ident(keyspace) || !ident(class) {
+            return None;
+        }
+        let mut replication = format!("{{'class' : '{}'", class);
+        for option in options.split(',').filter(|o| !o.trim().is_empty()) {
+            let (key, val) = option.split_once('=')?;
+            let (key, val) = (key.trim(), val.trim().parse::<u32>().ok()?);
+            if !ident(key) {
+                return None;
+            }
+            replication.push_str(&format!(", '{}' : {}", key, val));
+        }
+        replication.push('}');
+        Some(Self {
+            keyspace: keyspace.to_string(),
+            replication,
+        })
+    }
+    pub fn statement(&self, statement: &str) -> String {
+        statement.replace("{ks}", &self.keyspace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_strictly_increasing() {
+        assert!(MIGRATIONS.first().is_some_and(|m| m.version == 1));
+        assert!(MIGRATIONS.windows(2).all(|m| m[0].version < m[1].version));
+        assert_eq!(latest_version(), MIGRATIONS.last().unwrap().version);
+    }
+
+    #[test]
+    fn migrations_idempotent_and_keyspace_scoped() {
+        for statement in MIGRATIONS.iter().flat_map(|m| m.statements.iter()) {
+            assert!(statement.contains("IF NOT EXISTS {ks}."), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn schema_replication_options() {
+        let schema =
+            SchemaConfig::new("vplace", "NetworkTopologyStrategy", " dc1 = 3, dc2=2,").unwrap();
+        assert_eq!(schema.keyspace, "vplace");
+        assert_eq!(
+            schema.replication,
+            "{'class' : 'NetworkTopologyStrategy', 'dc1' : 3, 'dc2' : 2}"
+        );
+        let schema = SchemaConfig::new("vplace", "SimpleStrategy", "").unwrap();
+        assert_eq!(schema.replication, "{'class' : 'SimpleStrategy'}");
+        assert_eq!(
+            schema.statement("SELECT * FROM {ks}.player"),
+            "SELECT * FROM vplace.player"
+        );
+    }
+
+    #[test]
+    fn schema_rejects_injection() {
+        let invalid = [
+            ("vplace; DROP KEYSPACE x", "SimpleStrategy", ""),
+            ("", "SimpleStrategy", ""),
+            (&"k".repeat(49), "SimpleStrategy", ""),
+            ("vplace", "SimpleStrategy'}", ""),
+            ("vplace", "SimpleStrategy", "replication_factor=1}"),
+            ("vplace", "SimpleStrategy", "dc1' : 1, 'x=1"),
+            ("vplace", "SimpleStrategy", "replication_factor=-1"),
+            ("vplace", "SimpleStrategy", "replication_factor"),
+        ];
+        for (keyspace, class, options) in invalid {
+            assert!(
+                SchemaConfig::new(keyspace, class, options).is_none(),
+                "{} {} {}",
+                keyspace,
+                class,
+                options
+            );
+        }
+    }
+}
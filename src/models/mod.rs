@@ -1,3 +1,15 @@
+pub mod cache_models;
+pub mod challenge_models;
+pub mod cooldown_models;
 pub mod err_models;
+pub mod generation_models;
+pub mod health_models;
+pub mod metrics_models;
+pub mod migration_models;
 pub mod p_models;
+pub mod raid_models;
+pub mod rate_models;
+pub mod redis_models;
 pub mod scylla_models;
+pub mod tile_models;
+pub mod validation_models;
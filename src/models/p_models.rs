@@ -1,12 +1,32 @@
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
 
-use actix::{Actor, Addr, AsyncContext, Message};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Message, MessageResponse};
 use actix_web::web;
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
+use super::cache_models::CanvasCache;
+use super::challenge_models::ChallengeVerifier;
+use super::cooldown_models::CooldownPolicy;
+use super::err_models::ErrorBody;
+use super::metrics_models::METRICS;
+use super::raid_models::RaidDetector;
+use super::rate_models::{RateLimitConfig, RateLimited};
+use super::redis_models::RedisManager;
+use super::scylla_models::ScyllaManager;
+use super::validation_models::UnameRules;
+
+// no. of recent updates kept by VpSrv to resume SSE clients
+pub const UPDATE_BACKLOG: usize = 1024;
+// no. of pending events per SSE client before it is dropped
+pub const SSE_BUFFER: usize = 256;
+
 #[derive(Deserialize)]
 pub struct UpdatePixel {
     pub uid: Uuid,
@@ -14,14 +34,167 @@ pub struct UpdatePixel {
     // coordinates : (x,y)
     pub loc: (u32, u32),
     pub color: u8,
+    // anti-bot challenge proof, required for new or flagged users
+    #[serde(default)]
+    pub proof: Option<String>,
 }
 
-#[derive(Message, Serialize)]
+#[derive(Message, Serialize, Deserialize, Clone)]
 #[rtype(result = "()")]
 pub struct PlaceUpdate {
     // coordinates : (x,y)
     pub loc: (u32, u32),
     pub color: u8,
+    // canvas version after this update
+    pub version: u64,
+    // canvas generation pixel landed in
+    pub generation: u64,
+}
+
+// Connected clients and recent painters across all instances
+#[derive(Message, MessageResponse, Serialize, Deserialize, Clone, Copy, Default)]
+#[rtype(result = "()")]
+pub struct Presence {
+    pub online: usize,
+    // uids that placed a pixel within the presence window
+    pub painters: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasState {
+    Scheduled,
+    #[default]
+    Open,
+    Paused,
+    Closed,
+}
+
+// Canvas lifecycle set by admin, start and end are unix timestamps
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct CanvasLifecycle {
+    pub state: CanvasState,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+impl CanvasLifecycle {
+    // effective state at `now` after applying start and end times
+    pub fn state_at(&self, now: i64) -> CanvasState {
+        use CanvasState::*;
+        match self.state {
+            Paused | Closed => self.state,
+            _ if self.end.is_some_and(|end| now >= end) => Closed,
+            _ if self.start.is_some_and(|start| now < start) => Scheduled,
+            Scheduled if self.start.is_none() => Scheduled,
+            _ => Open,
+        }
+    }
+}
+
+// what was reset , returned by /reset
+#[derive(Serialize)]
+pub struct ResetReport {
+    // generation of the new (blank) canvas
+    pub generation: u64,
+    // ended generation , still readable at /canvases/{id}/generations/{n}
+    pub previous_generation: u64,
+    // version of canvas before reset
+    pub previous_version: u64,
+    pub version: u64,
+    pub redis_keys: Vec<String>,
+    pub scylla_tables: Vec<String>,
+    // player/credit cleanup failures , reset itself went through
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cleanup_errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CanvasStateResponse {
+    pub state: CanvasState,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+// body of /admin/broadcast , other system events are only sent by v-place itself
+#[derive(Deserialize)]
+pub struct Announcement {
+    pub message: String,
+}
+
+// System messages pushed by admin (or by v-place itself)
+// pause/resume go through /admin/canvas/state , which broadcasts StateChanged
+#[derive(Message, Serialize, Deserialize, Clone)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SysEvent {
+    Announcement {
+        message: String,
+    },
+    // new generation started at canvas `version` , clients should clear their local canvas
+    CanvasReset {
+        generation: u64,
+        version: u64,
+    },
+    CooldownChanged {
+        cooldown: usize,
+    },
+    StateChanged {
+        state: CanvasState,
+        start: Option<i64>,
+        end: Option<i64>,
+    },
+}
+
+// Events sent to /vplace and /events listeners
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VpEvent {
+    Place(PlaceUpdate),
+    Presence(Presence),
+    System { event: SysEvent },
+}
+impl VpEvent {
+    // SSE frame of the event, place events carry canvas version as id : )
+    pub fn sse(&self) -> Option<web::Bytes> {
+        let data = serde_json::to_string(self).ok()?;
+        let frame = match self {
+            VpEvent::Place(update) => {
+                format!("id: {}\nevent: place\ndata: {}\n\n", update.version, data)
+            }
+            VpEvent::Presence(_) => format!("event: presence\ndata: {}\n\n", data),
+            VpEvent::System { .. } => format!("event: system\ndata: {}\n\n", data),
+        };
+        Some(web::Bytes::from(frame))
+    }
+}
+
+// VpEvent relayed to other v-place instances over redis pub/sub
+// origin : instance_id of the publisher, used to skip our own events : )
+#[derive(Serialize, Deserialize)]
+pub struct PubEvent {
+    pub origin: Uuid,
+    pub event: VpEvent,
+}
+
+// representation of /canvas , base64 json or raw bitfield bytes
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CanvasFormat {
+    Json,
+    Raw,
+}
+
+#[derive(Deserialize)]
+pub struct CanvasQuery {
+    // overrides Accept header
+    pub format: Option<CanvasFormat>,
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    pub since: u64,
+    // overrides Accept header
+    pub format: Option<CanvasFormat>,
 }
 
 #[derive(Serialize)]
@@ -30,12 +203,79 @@ pub struct CanvasResponse<'a> {
     pub dim: u32,
     pub canvas: &'a str,
     pub cooldown: usize,
+    // `since` of next /canvas/diff
+    pub version: u64,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct WaitTime {
     pub rem_wait: i64,
+    // effective cooldown of the user
+    pub cooldown: usize,
+    #[serde(flatten)]
+    pub credits: Option<Credits>,
+}
+
+// Pixel credit balance of user, only in burst mode
+// next_refill : unix timestamp of next credit, None if bucket is full
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Credits {
+    pub credits: u64,
+    pub max_credits: u64,
+    pub next_refill: Option<i64>,
+}
+
+pub enum Placement {
+    Placed(Option<Credits>),
+    Wait(WaitTime),
+}
+
+// Reply to a pixel update sent over /vplace
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaceReply {
+    Placed {
+        #[serde(flatten)]
+        credits: Option<Credits>,
+    },
+    Wait(WaitTime),
+    RateLimited(RateLimited),
+    Error(ErrorBody),
+}
+
+// WebSocket listener config
+#[derive(Clone, Copy)]
+pub struct WsConfig {
+    // interval between server pings
+    pub heartbeat: Duration,
+    // client is dropped if nothing is heard within this duration
+    pub client_timeout: Duration,
+    // max. updates client is behind by , counting only updates sent
+    // more than a heartbeat ago and not yet acknowledged (via pong)
+    pub max_buffer: u64,
+}
+impl WsConfig {
+    // Err if clients could time out between two pings
+    pub fn new(
+        heartbeat: Duration,
+        client_timeout: Duration,
+        max_buffer: u64,
+    ) -> Result<Self, String> {
+        if client_timeout <= heartbeat {
+            return Err(format!(
+                "WS_TIMEOUT ({}s) should be longer than WS_HEARTBEAT ({}s)",
+                client_timeout.as_secs(),
+                heartbeat.as_secs()
+            ));
+        }
+        Ok(Self {
+            heartbeat,
+            client_timeout,
+            max_buffer,
+        })
+    }
 }
 
 //AppState
@@ -45,7 +285,15 @@ pub struct AppState<'a> {
     //admin Id
     pub admin_token: Cow<'a, str>,
     pub canvas_dim: u32,
+    // default cooldown, can be changed at runtime by admin
     pub cooldown: usize,
+    pub cooldown_policy: CooldownPolicy,
+    pub ws_conf: WsConfig,
+    pub uname_rules: UnameRules,
+    // unique id of this v-place instance
+    pub instance_id: Uuid,
+    // last seen canvas generation , generation scripts try its keys first
+    pub generation: AtomicU64,
 }
 impl<'a> AppState<'a> {
     pub fn new(
@@ -53,39 +301,253 @@ impl<'a> AppState<'a> {
         canvas_id: Cow<'a, str>,
         canvas_dim: u32,
         cooldown: usize,
+        cooldown_policy: CooldownPolicy,
+        ws_conf: WsConfig,
+        uname_rules: UnameRules,
     ) -> Self {
         Self {
             admin_token,
             canvas_id,
             canvas_dim,
             cooldown,
+            cooldown_policy,
+            ws_conf,
+            uname_rules,
+            instance_id: Uuid::new_v4(),
+            generation: AtomicU64::new(0),
         }
     }
+    // redis pub/sub channel for pixel updates of this canvas
+    pub fn update_channel(&self) -> String {
+        format!("{}:updates", self.canvas_id)
+    }
+    // redis key of canvas version, incremented on every pixel update
+    pub fn version_key(&self) -> String {
+        format!("{}:version", self.canvas_id)
+    }
+    // redis counter of canvas generation , moves on every reset
+    pub fn generation_key(&self) -> String {
+        format!("{}:generation", self.canvas_id)
+    }
+    // prefix of redis keys of a generation , `{root}{generation}:...`
+    pub fn generation_root(&self) -> String {
+        format!("{}:gen:", self.canvas_id)
+    }
+    // redis key of canvas bitfield of a generation
+    pub fn canvas_key(&self, generation: u64) -> String {
+        format!("{}{}:canvas", self.generation_root(), generation)
+    }
+    // redis hash of started_at, ended_at, final_version and placements of a generation
+    pub fn generation_stats_key(&self, generation: u64) -> String {
+        format!("{}{}:stats", self.generation_root(), generation)
+    }
+    // redis hyperloglog of uids which placed a pixel in a generation
+    pub fn generation_painters_key(&self, generation: u64) -> String {
+        format!("{}{}:painters", self.generation_root(), generation)
+    }
+    // redis key of canvas lifecycle (json)
+    pub fn lifecycle_key(&self) -> String {
+        format!("{}:lifecycle", self.canvas_id)
+    }
+    // redis key of cooldown set by admin at runtime
+    pub fn cooldown_key(&self) -> String {
+        format!("{}:cooldown", self.canvas_id)
+    }
+    // redis hash of pixel credits of user
+    pub fn credits_key(&self, uid: &Uuid) -> String {
+        format!("{}:credits:{}", self.canvas_id, uid)
+    }
+    // redis key pattern matching pixel credits of all users
+    pub fn credits_pattern(&self) -> String {
+        format!("{}:credits:*", self.canvas_id)
+    }
+    // redis counter of requests from ip/subnet `src` in rate limit window
+    pub fn rate_key(&self, src: &str, window: u64) -> String {
+        format!("{}:rl:{}:{}", self.canvas_id, src, window)
+    }
+    // redis key of an issued proof-of-work challenge
+    pub fn challenge_key(&self, challenge: &str) -> String {
+        format!("{}:challenge:{}", self.canvas_id, challenge)
+    }
+    // redis set of uids flagged as suspicious
+    pub fn flagged_key(&self) -> String {
+        format!("{}:flagged", self.canvas_id)
+    }
+    // redis hash of uid -> first placement (ms) , kept across resets
+    pub fn first_seen_key(&self) -> String {
+        format!("{}:first_seen", self.canvas_id)
+    }
+    // redis list of raid flags awaiting moderation (json)
+    pub fn modq_key(&self) -> String {
+        format!("{}:modq", self.canvas_id)
+    }
+    // redis set of shadow-banned uids
+    pub fn shadowban_key(&self) -> String {
+        format!("{}:shadowbanned", self.canvas_id)
+    }
+    // redis hash of uid -> user tier
+    pub fn tiers_key(&self) -> String {
+        format!("{}:tiers", self.canvas_id)
+    }
+    // redis counter of placements in the second `ts`
+    pub fn pps_key(&self, ts: i64) -> String {
+        format!("{}:pps:{}", self.canvas_id, ts)
+    }
+    // redis hash of connected client count per instance
+    pub fn online_key(&self) -> String {
+        format!("{}:online", self.canvas_id)
+    }
+    // redis sorted set of uids scored by last placement time
+    pub fn painters_key(&self) -> String {
+        format!("{}:painters", self.canvas_id)
+    }
 }
 
 // Pixel Update Server Actor
 pub struct VpSrv<'a: 'static> {
     pub listeners: HashSet<Addr<VpListener<'a>>>,
+    // Server-Sent Events listeners
+    pub sse_listeners: Vec<mpsc::Sender<web::Bytes>>,
+    // recent updates ordered by arrival, used to resume SSE clients
+    pub backlog: VecDeque<PlaceUpdate>,
+    // canvas version of last reset , SSE clients resuming from before it must resync
+    pub reset_version: u64,
+    // latest canvas generation seen , updates of ended generations are dropped
+    pub generation: u64,
+    // highest canvas version seen in updates and resets , 0 until first one
+    pub latest: u64,
+    // last presence computed by presence tracker
+    pub presence: Presence,
+    pub cache: web::Data<CanvasCache>,
 }
 impl<'a> VpSrv<'a> {
-    pub fn new() -> Self {
+    pub fn new(cache: web::Data<CanvasCache>) -> Self {
         VpSrv {
+            cache,
             listeners: HashSet::new(),
+            sse_listeners: Vec::new(),
+            backlog: VecDeque::with_capacity(UPDATE_BACKLOG),
+            reset_version: 0,
+            generation: 0,
+            latest: 0,
+            presence: Presence::default(),
         }
     }
+    pub fn broadcast(&mut self, event: &VpEvent) {
+        if let Ok(res) = serde_json::to_string(event) {
+            let res = Cow::from(res);
+            self.listeners
+                .iter()
+                .for_each(|addr| addr.do_send(VpRes(res.clone())));
+        }
+        if let Some(frame) = event.sse() {
+            // drop disconnected and lagging SSE clients, they can resume with Last-Event-ID
+            self.sse_listeners
+                .retain(|tx| tx.try_send(frame.clone()).is_ok());
+        }
+        self.record_metrics();
+    }
+    // SSE client resuming after `last_id` missed updates the backlog can't replay
+    pub fn missed(&self, last_id: u64) -> bool {
+        // backlog starts over on reset, so clients from before it can't catch up
+        if last_id < self.reset_version {
+            return true;
+        }
+        let current = match self.cache.version() {
+            Some(version) => version.max(self.latest),
+            // nothing seen yet , can't tell what client missed
+            None if self.latest == 0 => return true,
+            None => self.latest,
+        };
+        // backlog is ordered by arrival, so every version after last_id is looked for
+        let replayed: HashSet<u64> = self
+            .backlog
+            .iter()
+            .map(|update| update.version)
+            .filter(|version| *version > last_id)
+            .collect();
+        (replayed.len() as u64) < current.saturating_sub(last_id)
+    }
+    pub fn record_metrics(&self) {
+        let queued: usize = self
+            .sse_listeners
+            .iter()
+            .map(|tx| tx.max_capacity() - tx.capacity())
+            .sum();
+        METRICS.ws_clients.set(self.listeners.len() as i64);
+        METRICS.sse_clients.set(self.sse_listeners.len() as i64);
+        METRICS.broadcast_queue.set(queued as i64);
+    }
 }
 // Pixel Update Listener Actor
 pub struct VpListener<'a: 'static> {
-    srv_addr: web::Data<Addr<VpSrv<'a>>>,
+    pub srv_addr: web::Data<Addr<VpSrv<'a>>>,
     addr: Option<Addr<Self>>,
+    // used for pixel updates sent over websocket
+    pub app_data: web::Data<AppState<'a>>,
+    pub redis: web::Data<RedisManager>,
+    pub scylla: web::Data<ScyllaManager>,
+    pub rate_conf: web::Data<RateLimitConfig>,
+    pub verifier: web::Data<dyn ChallengeVerifier>,
+    pub detector: web::Data<RaidDetector>,
+    // client ip, used to rate limit pixel updates
+    pub ip: Option<IpAddr>,
+    pub conf: WsConfig,
+    // last time client was heard from
+    pub hb: Instant,
+    // no. of updates sent to client
+    pub sent: u64,
+    // no. of updates acknowledged by client
+    // pings carry `sent` as payload, so pong payload is the ack : )
+    pub acked: u64,
+    // `sent` at last ping and at the ping before it
+    // a healthy client has acked the previous ping , whatever the update rate
+    pub pinged: u64,
+    pub overdue: u64,
 }
 impl<'a> VpListener<'a> {
-    pub fn new(srv_addr: web::Data<Addr<VpSrv<'a>>>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        srv_addr: web::Data<Addr<VpSrv<'a>>>,
+        app_data: web::Data<AppState<'a>>,
+        redis: web::Data<RedisManager>,
+        scylla: web::Data<ScyllaManager>,
+        rate_conf: web::Data<RateLimitConfig>,
+        verifier: web::Data<dyn ChallengeVerifier>,
+        detector: web::Data<RaidDetector>,
+        ip: Option<IpAddr>,
+    ) -> Self {
+        let conf = app_data.ws_conf;
         Self {
             srv_addr,
             addr: None,
+            app_data,
+            redis,
+            scylla,
+            rate_conf,
+            verifier,
+            detector,
+            ip,
+            conf,
+            hb: Instant::now(),
+            sent: 0,
+            acked: 0,
+            pinged: 0,
+            overdue: 0,
         }
     }
+    fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.conf.heartbeat, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.conf.client_timeout {
+                tracing::debug!("Client heartbeat timed out, disconnecting");
+                ctx.stop();
+                return;
+            }
+            act.overdue = act.pinged;
+            act.pinged = act.sent;
+            ctx.ping(&act.sent.to_be_bytes());
+        });
+    }
 }
 
 #[derive(Message)]
@@ -96,6 +558,23 @@ pub struct VpConnect<'a: 'static>(pub Addr<VpListener<'a>>);
 #[rtype(result = "()")]
 pub struct VpDisconnect<'a: 'static>(pub Addr<VpListener<'a>>);
 
+// last_id : Last-Event-ID sent by a reconnecting client
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct VpSseConnect {
+    pub tx: mpsc::Sender<web::Bytes>,
+    pub last_id: Option<u64>,
+}
+
+// no. of clients connected to this instance
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct VpCount;
+
+#[derive(Message)]
+#[rtype(result = "Presence")]
+pub struct GetPresence;
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct VpRes<'a>(pub Cow<'a, str>);
@@ -106,6 +585,7 @@ impl<'a> Actor for VpListener<'a> {
         let addr = ctx.address();
         self.addr = Some(addr.clone());
         self.srv_addr.do_send(VpConnect(addr));
+        self.heartbeat(ctx);
     }
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         if let Some(addr) = &self.addr {
@@ -116,3 +596,68 @@ impl<'a> Actor for VpListener<'a> {
 impl<'a> Actor for VpSrv<'a> {
     type Context = actix::Context<Self>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tile_models::TileConfig;
+
+    fn srv() -> VpSrv<'static> {
+        VpSrv::new(web::Data::new(CanvasCache::new(4, TileConfig::new(2, ""))))
+    }
+
+    fn place(srv: &mut VpSrv<'_>, version: u64) {
+        srv.latest = srv.latest.max(version);
+        srv.backlog.push_back(PlaceUpdate {
+            loc: (0, 0),
+            color: 1,
+            version,
+            generation: 0,
+        });
+    }
+
+    #[test]
+    fn ws_timeout_after_heartbeat() {
+        let secs = Duration::from_secs;
+        assert!(WsConfig::new(secs(5), secs(15), 0).is_ok());
+        assert!(WsConfig::new(secs(5), secs(5), 0).is_err());
+        assert!(WsConfig::new(secs(15), secs(5), 0).is_err());
+    }
+
+    #[test]
+    fn unknown_version_resyncs() {
+        assert!(srv().missed(5));
+    }
+
+    #[test]
+    fn empty_backlog_behind_canvas() {
+        let srv = srv();
+        srv.cache.load(vec![0; 8], 10, 0);
+        assert!(srv.missed(9));
+        assert!(!srv.missed(10));
+        assert!(!srv.missed(12));
+    }
+
+    #[test]
+    fn backlog_replays_out_of_order_updates() {
+        let mut srv = srv();
+        srv.cache.load(vec![0; 8], 10, 0);
+        place(&mut srv, 12);
+        place(&mut srv, 11);
+        assert!(!srv.missed(10));
+        assert!(srv.missed(9));
+        // 13 hasn't arrived yet
+        place(&mut srv, 14);
+        assert!(srv.missed(10));
+    }
+
+    #[test]
+    fn clients_from_before_reset_resync() {
+        let mut srv = srv();
+        srv.reset_version = 20;
+        srv.latest = 20;
+        place(&mut srv, 21);
+        assert!(srv.missed(19));
+        assert!(!srv.missed(20));
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// prune stale detector state once every this many placements
+const PRUNE_EVERY: u64 = 1024;
+
+// Raid detection config , 0 disables a heuristic
+pub struct RaidConfig {
+    // max. distinct new uids from one ip within `ip_window` secs
+    pub ip_uids: usize,
+    pub ip_window: i64,
+    // uids first seen within this many secs count as new accounts
+    pub new_age: i64,
+    // no. of placement intervals checked for periodic timing
+    pub periodic_samples: usize,
+    // max. spread (ms) of intervals considered perfectly periodic
+    pub periodic_jitter_ms: i64,
+    // square region side length used to detect coordinated placements
+    pub region_size: u32,
+    // min. new accounts placing in one region within `region_window` secs
+    pub region_accounts: usize,
+    pub region_window: i64,
+    // shadow-ban flagged uids automatically
+    pub auto_shadowban: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagReason {
+    // many new uids from one ip
+    IpUids,
+    // perfectly periodic placement timing
+    PeriodicTiming,
+    // many new accounts painting one region
+    Coordinated,
+}
+
+// Entry of moderation queue
+#[derive(Serialize, Deserialize)]
+pub struct Flag {
+    pub reason: FlagReason,
+    pub uids: Vec<Uuid>,
+    pub ip: Option<IpAddr>,
+    // region (rx,ry) for coordinated placements
+    pub region: Option<(u32, u32)>,
+    // unix timestamp
+    pub at: i64,
+}
+
+#[derive(Default)]
+struct RaidState {
+    observed: u64,
+    // ip -> (uid, placed at (ms))
+    ips: HashMap<IpAddr, VecDeque<(Uuid, i64)>>,
+    // uid -> recent placement times (ms)
+    timings: HashMap<Uuid, VecDeque<i64>>,
+    // region -> (uid, placed at (ms))
+    regions: HashMap<(u32, u32), VecDeque<(Uuid, i64)>>,
+}
+
+// Analyzer over the stream of pixel updates of this instance
+pub struct RaidDetector {
+    pub conf: RaidConfig,
+    state: Mutex<RaidState>,
+}
+impl RaidDetector {
+    pub fn new(conf: RaidConfig) -> Self {
+        Self {
+            conf,
+            state: Mutex::new(RaidState::default()),
+        }
+    }
+    // account first seen (ms) within new_age, unknown ones aren't new
+    fn is_new(&self, first_seen: Option<i64>, now: i64) -> bool {
+        first_seen.is_some_and(|first| now - first < self.conf.new_age * 1000)
+    }
+    // record a pixel update at `now` (ms) by uid first seen at `first_seen` (ms), returns raised flags
+    pub fn observe(
+        &self,
+        uid: Uuid,
+        first_seen: Option<i64>,
+        ip: Option<IpAddr>,
+        loc: (u32, u32),
+        now: i64,
+    ) -> Vec<Flag> {
+        let mut flags = Vec::new();
+        let Ok(mut state) = self.state.lock() else {
+            return flags;
+        };
+        let state = &mut *state;
+        state.observed += 1;
+        if state.observed % PRUNE_EVERY == 0 {
+            self.prune(state, now);
+        }
+        let new = self.is_new(first_seen, now);
+
+        // many new uids from one ip
+        if let (Some(ip), true) = (ip, new && self.conf.ip_uids > 0) {
+            let window = self.conf.ip_window * 1000;
+            let seen = state.ips.entry(ip).or_default();
+            seen.retain(|(_, at)| now - at < window);
+            if !seen.iter().any(|(seen_uid, _)| seen_uid.eq(&uid)) {
+                seen.push_back((uid, now));
+                if seen.len() > self.conf.ip_uids {
+                    flags.push(Flag {
+                        reason: FlagReason::IpUids,
+                        uids: seen.iter().map(|(uid, _)| *uid).collect(),
+                        ip: Some(ip),
+                        region: None,
+                        at: now / 1000,
+                    });
+                    seen.clear();
+                }
+            }
+        }
+
+        // perfectly periodic placement timing
+        if self.conf.periodic_samples > 0 {
+            let timings = state.timings.entry(uid).or_default();
+            timings.push_back(now);
+            if timings.len() > self.conf.periodic_samples + 1 {
+                timings.pop_front();
+            }
+            if timings.len() == self.conf.periodic_samples + 1 {
+                let intervals = timings
+                    .iter()
+                    .zip(timings.iter().skip(1))
+                    .map(|(prev, next)| next - prev);
+                let (min, max) = intervals.fold((i64::MAX, i64::MIN), |(min, max), i| {
+                    (min.min(i), max.max(i))
+                });
+                if max - min <= self.conf.periodic_jitter_ms {
+                    flags.push(Flag {
+                        reason: FlagReason::PeriodicTiming,
+                        uids: vec![uid],
+                        ip,
+                        region: None,
+                        at: now / 1000,
+                    });
+                    timings.clear();
+                }
+            }
+        }
+
+        // many new accounts painting one region
+        if self.conf.region_accounts > 0 && self.conf.region_size > 0 {
+            let region = (loc.0 / self.conf.region_size, loc.1 / self.conf.region_size);
+            let window = self.conf.region_window * 1000;
+            let placed = state.regions.entry(region).or_default();
+            placed.retain(|(_, at)| now - at < window);
+            // only new accounts are tracked per region
+            if new {
+                placed.push_back((uid, now));
+            }
+            let new_uids: HashSet<Uuid> = placed.iter().map(|(uid, _)| *uid).collect();
+            if new_uids.len() >= self.conf.region_accounts {
+                flags.push(Flag {
+                    reason: FlagReason::Coordinated,
+                    uids: new_uids.into_iter().collect(),
+                    ip: None,
+                    region: Some(region),
+                    at: now / 1000,
+                });
+                placed.clear();
+            }
+        }
+        flags
+    }
+    fn prune(&self, state: &mut RaidState, now: i64) {
+        let ip_window = self.conf.ip_window * 1000;
+        let region_window = self.conf.region_window * 1000;
+        state.ips.retain(|_, seen| {
+            seen.retain(|(_, at)| now - at < ip_window);
+            !seen.is_empty()
+        });
+        // users who stopped placing can't be periodic anymore : )
+        state
+            .timings
+            .retain(|_, timings| timings.back().is_some_and(|last| now - last < ip_window));
+        state.regions.retain(|_, placed| {
+            placed.retain(|(_, at)| now - at < region_window);
+            !placed.is_empty()
+        });
+    }
+}
+
+// newest flags are listed first
+#[derive(Deserialize)]
+pub struct ModerationQuery {
+    // clamped to 1..=1000
+    #[serde(default = "default_count")]
+    pub count: usize,
+}
+fn default_count() -> usize {
+    100
+}
+
+#[derive(Deserialize)]
+pub struct UpdateShadowban {
+    pub uid: Uuid,
+    pub banned: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3600 * 1000;
+
+    fn detector(ip_uids: usize, region_accounts: usize) -> RaidDetector {
+        RaidDetector::new(RaidConfig {
+            ip_uids,
+            ip_window: 600,
+            new_age: 3600,
+            periodic_samples: 0,
+            periodic_jitter_ms: 50,
+            region_size: 16,
+            region_accounts,
+            region_window: 60,
+            auto_shadowban: true,
+        })
+    }
+
+    fn ip() -> Option<IpAddr> {
+        Some("203.0.113.7".parse().unwrap())
+    }
+
+    fn reasons(flags: &[Flag]) -> Vec<String> {
+        flags
+            .iter()
+            .map(|flag| format!("{:?}", flag.reason))
+            .collect()
+    }
+
+    #[test]
+    fn ip_flagged_past_threshold() {
+        let detector = detector(3, 0);
+        let now = 10 * HOUR;
+        let uids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        for uid in &uids[..3] {
+            assert!(detector
+                .observe(*uid, Some(now), ip(), (0, 0), now)
+                .is_empty());
+        }
+        // same uid again isn't another account
+        assert!(detector
+            .observe(uids[0], Some(now), ip(), (0, 0), now)
+            .is_empty());
+        let flags = detector.observe(uids[3], Some(now), ip(), (0, 0), now);
+        assert_eq!(reasons(&flags), ["IpUids"]);
+        assert_eq!(flags[0].uids.len(), 4);
+        assert_eq!(flags[0].ip, ip());
+    }
+
+    #[test]
+    fn ip_ignores_old_and_unknown_accounts() {
+        let detector = detector(1, 0);
+        let now = 10 * HOUR;
+        for _ in 0..5 {
+            assert!(detector
+                .observe(Uuid::new_v4(), Some(now - 2 * HOUR), ip(), (0, 0), now)
+                .is_empty());
+            assert!(detector
+                .observe(Uuid::new_v4(), None, ip(), (0, 0), now)
+                .is_empty());
+        }
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(now), ip(), (0, 0), now)
+            .is_empty());
+        assert_eq!(
+            reasons(&detector.observe(Uuid::new_v4(), Some(now), ip(), (0, 0), now)),
+            ["IpUids"]
+        );
+    }
+
+    #[test]
+    fn ip_window_expires() {
+        let detector = detector(1, 0);
+        let now = 10 * HOUR;
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(now), ip(), (0, 0), now)
+            .is_empty());
+        let later = now + 600 * 1000;
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(later), ip(), (0, 0), later)
+            .is_empty());
+    }
+
+    #[test]
+    fn region_flagged_at_threshold() {
+        let detector = detector(0, 3);
+        let now = 10 * HOUR;
+        // old accounts painting the region don't count
+        for _ in 0..5 {
+            assert!(detector
+                .observe(Uuid::new_v4(), Some(0), None, (1, 1), now)
+                .is_empty());
+        }
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(now), None, (2, 2), now)
+            .is_empty());
+        // other region
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(now), None, (20, 2), now)
+            .is_empty());
+        assert!(detector
+            .observe(Uuid::new_v4(), Some(now), None, (3, 3), now)
+            .is_empty());
+        let flags = detector.observe(Uuid::new_v4(), Some(now), None, (15, 15), now);
+        assert_eq!(reasons(&flags), ["Coordinated"]);
+        assert_eq!(flags[0].uids.len(), 3);
+        assert_eq!(flags[0].region, Some((0, 0)));
+    }
+
+    #[test]
+    fn periodic_timing() {
+        let mut detector = detector(0, 0);
+        detector.conf.periodic_samples = 3;
+        let uid = Uuid::new_v4();
+        let now = 10 * HOUR;
+        for (i, jitter) in [0, 10, -20].into_iter().enumerate() {
+            let at = now + i as i64 * 5000 + jitter;
+            assert!(detector.observe(uid, None, None, (0, 0), at).is_empty());
+        }
+        let flags = detector.observe(uid, None, None, (0, 0), now + 15000);
+        assert_eq!(reasons(&flags), ["PeriodicTiming"]);
+
+        let human = Uuid::new_v4();
+        for at in [0, 5000, 11000, 15000] {
+            assert!(detector
+                .observe(human, None, None, (0, 0), now + at)
+                .is_empty());
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use actix_web::HttpRequest;
+use serde::Serialize;
+
+// ip address or network , eg: 10.0.0.1 , 10.0.0.0/8
+pub struct IpNet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+impl IpNet {
+    pub fn parse(net: &str) -> Option<Self> {
+        let (addr, prefix) = match net.trim().split_once('/') {
+            Some((addr, prefix)) => (
+                addr.parse::<IpAddr>().ok()?,
+                Some(prefix.parse::<u8>().ok()?),
+            ),
+            None => (net.trim().parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        // prefix longer than the address
+        if prefix > max {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.addr.is_ipv4() == ip.is_ipv4()
+            && mask(*ip, self.prefix) == mask(self.addr, self.prefix)
+    }
+}
+
+// zero out host bits of ip after first `prefix` bits
+pub fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix.min(32)))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix.min(128)))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// Per ip and per subnet rate limit of pixel updates
+pub struct RateLimitConfig {
+    // max. requests per ip in a window, 0 disables
+    pub ip_limit: u64,
+    // max. requests per subnet in a window, 0 disables
+    pub subnet_limit: u64,
+    // window size in seconds
+    pub window: u64,
+    pub v4_prefix: u8,
+    pub v6_prefix: u8,
+    // proxies allowed to set X-Forwarded-For
+    pub trusted_proxies: Vec<IpNet>,
+}
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.ip_limit > 0 || self.subnet_limit > 0
+    }
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+    // client ip, X-Forwarded-For is only honoured when peer is a trusted proxy
+    // rightmost untrusted hop is the client : )
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+        let forwarded = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|xff| xff.to_str().ok())
+            .unwrap_or_default();
+        let mut client = peer;
+        for hop in forwarded.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.is_trusted(&ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+    pub fn subnet(&self, ip: IpAddr) -> String {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.v4_prefix,
+            IpAddr::V6(_) => self.v6_prefix,
+        };
+        format!("{}/{}", mask(ip, prefix), prefix)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimited {
+    pub retry_after: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn conf(trusted: &[&str]) -> RateLimitConfig {
+        RateLimitConfig {
+            ip_limit: 10,
+            subnet_limit: 100,
+            window: 60,
+            v4_prefix: 24,
+            v6_prefix: 64,
+            trusted_proxies: trusted.iter().filter_map(|net| IpNet::parse(net)).collect(),
+        }
+    }
+
+    fn client_ip(conf: &RateLimitConfig, peer: Option<&str>, xff: Option<&str>) -> Option<IpAddr> {
+        let mut req = TestRequest::default();
+        if let Some(peer) = peer {
+            req = req.peer_addr(format!("{}:4000", peer).parse().unwrap());
+        }
+        if let Some(xff) = xff {
+            req = req.insert_header(("X-Forwarded-For", xff));
+        }
+        conf.client_ip(&req.to_http_request())
+    }
+
+    #[test]
+    fn parse_nets() {
+        let net = IpNet::parse(" 10.0.0.0/8 ").unwrap();
+        assert_eq!((net.addr, net.prefix), (ip("10.0.0.0"), 8));
+        assert!(net.contains(&ip("10.200.1.1")));
+        assert!(!net.contains(&ip("11.0.0.1")));
+        assert!(!net.contains(&ip("::a00:1")));
+        assert_eq!(IpNet::parse("10.0.0.1").unwrap().prefix, 32);
+        assert_eq!(IpNet::parse("fd00::1").unwrap().prefix, 128);
+        assert_eq!(IpNet::parse("fd00::/8").unwrap().prefix, 8);
+    }
+
+    #[test]
+    fn parse_invalid_nets() {
+        for net in [
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0/8",
+            "10.0.0.0/-1",
+            "proxy",
+        ] {
+            assert!(IpNet::parse(net).is_none(), "{}", net);
+        }
+    }
+
+    #[test]
+    fn v4_subnet_mask() {
+        assert_eq!(mask(ip("192.168.13.77"), 24), ip("192.168.13.0"));
+        assert_eq!(mask(ip("192.168.13.77"), 0), ip("0.0.0.0"));
+        assert_eq!(mask(ip("192.168.13.77"), 32), ip("192.168.13.77"));
+        assert_eq!(conf(&[]).subnet(ip("192.168.13.77")), "192.168.13.0/24");
+    }
+
+    #[test]
+    fn v6_subnet_mask() {
+        let addr = ip("2001:db8:1:2:3:4:5:6");
+        assert_eq!(mask(addr, 64), ip("2001:db8:1:2::"));
+        assert_eq!(mask(addr, 128), addr);
+        assert_eq!(conf(&[]).subnet(addr), "2001:db8:1:2::/64");
+        // hosts of one /64 share a subnet
+        assert_eq!(
+            conf(&[]).subnet(ip("2001:db8:1:2:ffff::1")),
+            conf(&[]).subnet(addr)
+        );
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_xff() {
+        let conf = conf(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip(&conf, Some("203.0.113.9"), Some("198.51.100.1")),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_xff_ignored() {
+        let conf = conf(&["10.0.0.0/8"]);
+        // client sent "1.2.3.4" itself , proxy appended the real client ip
+        assert_eq!(
+            client_ip(
+                &conf,
+                Some("10.0.0.2"),
+                Some("1.2.3.4, 203.0.113.9, 10.0.0.1")
+            ),
+            Some(ip("203.0.113.9"))
+        );
+        assert_eq!(
+            client_ip(&conf, Some("10.0.0.2"), Some("garbage, 203.0.113.9")),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn all_hops_trusted() {
+        let conf = conf(&["10.0.0.0/8"]);
+        assert_eq!(
+            client_ip(&conf, Some("10.0.0.2"), Some("10.0.0.5, 10.0.0.1")),
+            Some(ip("10.0.0.5"))
+        );
+        // no header , peer is all we know
+        assert_eq!(
+            client_ip(&conf, Some("10.0.0.2"), None),
+            Some(ip("10.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn missing_peer_address() {
+        let conf = conf(&["10.0.0.0/8"]);
+        assert_eq!(client_ip(&conf, None, Some("203.0.113.9")), None);
+    }
+}
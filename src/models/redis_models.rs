@@ -0,0 +1,200 @@
+use std::io;
+use std::time::Duration;
+
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{Client, Cmd, Pipeline, RedisError, RedisFuture, Value};
+
+use super::err_models::VpError;
+
+// Redis connection config
+pub struct RedisConfig {
+    // reconnect attempts after connection is lost
+    pub retries: usize,
+    // reconnect delay (ms) : rand(0 .. backoff_factor * backoff_base ^ attempt)
+    pub backoff_base: u64,
+    pub backoff_factor: u64,
+    pub connect_timeout: Duration,
+    // max. wait for a command/pipeline reply
+    pub response_timeout: Duration,
+}
+
+// Long lived redis connection shared by all workers
+// cheap to clone, reconnects with backoff when connection is lost : )
+#[derive(Clone)]
+pub struct RedisManager {
+    conn: ConnectionManager,
+    response_timeout: Duration,
+}
+impl RedisManager {
+    pub async fn connect(client: Client, conf: &RedisConfig) -> Result<Self, VpError> {
+        let conn = tokio::time::timeout(
+            conf.connect_timeout,
+            ConnectionManager::new_with_backoff(
+                client,
+                conf.backoff_base,
+                conf.backoff_factor,
+                conf.retries,
+            ),
+        )
+        .await
+        .map_err(|_| timed_out("connect"))??;
+        Ok(Self {
+            conn,
+            response_timeout: conf.response_timeout,
+        })
+    }
+}
+
+fn timed_out(op: &str) -> RedisError {
+    RedisError::from(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("redis {} timed out", op),
+    ))
+}
+
+impl ConnectionLike for RedisManager {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let timeout = self.response_timeout;
+        Box::pin(async move {
+            tokio::time::timeout(timeout, self.conn.req_packed_command(cmd))
+                .await
+                .unwrap_or_else(|_| Err(timed_out("command")))
+        })
+    }
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let timeout = self.response_timeout;
+        Box::pin(async move {
+            tokio::time::timeout(timeout, self.conn.req_packed_commands(cmd, offset, count))
+                .await
+                .unwrap_or_else(|_| Err(timed_out("pipeline")))
+        })
+    }
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
+// Runs redis lua scripts against an in-memory stub of the few commands they use
+#[cfg(test)]
+pub mod script_stub {
+    use mlua::{FromLuaMulti, Lua};
+
+    const STUB: &str = r"
+store = {}
+local function hash(key)
+  store[key] = store[key] or {}
+  return store[key]
+end
+redis = { call = function(cmd, key, ...)
+  local args = {...}
+  if cmd == 'GET' then
+    return store[key] or false
+  elseif cmd == 'SET' then
+    store[key] = tostring(args[1])
+    return 'OK'
+  elseif cmd == 'INCR' then
+    store[key] = tostring((tonumber(store[key]) or 0) + 1)
+    return tonumber(store[key])
+  elseif cmd == 'EXISTS' then
+    return store[key] and 1 or 0
+  elseif cmd == 'DEL' then
+    local existed = store[key] and 1 or 0
+    store[key] = nil
+    return existed
+  elseif cmd == 'EXPIRE' then
+    return store[key] and 1 or 0
+  elseif cmd == 'TYPE' then
+    return { ok = type(store[key]) == 'string' and 'string' or (store[key] and 'hash' or 'none') }
+  elseif cmd == 'RENAME' then
+    store[args[1]], store[key] = store[key], nil
+    return 'OK'
+  elseif cmd == 'SETRANGE' then
+    local s = store[key] or ''
+    local offset = tonumber(args[1])
+    if #s < offset then s = s .. string.rep('\0', offset - #s) end
+    store[key] = s:sub(1, offset) .. args[2] .. s:sub(offset + #args[2] + 1)
+    return #store[key]
+  elseif cmd == 'BITFIELD' then
+    -- only SET u4 #n
+    local s, n, v = store[key] or '', tonumber(args[3]:sub(2)), tonumber(args[4])
+    local i = math.floor(n / 2) + 1
+    if #s < i then s = s .. string.rep('\0', i - #s) end
+    local b = s:byte(i)
+    b = n % 2 == 0 and (v * 16 + b % 16) or (b - b % 16 + v)
+    store[key] = s:sub(1, i - 1) .. string.char(b) .. s:sub(i + 1)
+    return {0}
+  elseif cmd == 'HSETNX' then
+    local h = hash(key)
+    if h[args[1]] then return 0 end
+    h[args[1]] = tostring(args[2])
+    return 1
+  elseif cmd == 'PFADD' or cmd == 'ZADD' then
+    -- members only
+    local h = hash(key)
+    h[args[#args]] = '1'
+    return 1
+  elseif cmd == 'HGET' then
+    return hash(key)[args[1]] or false
+  elseif cmd == 'HMGET' then
+    local h, res = hash(key), {}
+    for i, field in ipairs(args) do res[i] = h[field] or false end
+    return res
+  elseif cmd == 'HSET' then
+    local h = hash(key)
+    for i = 1, #args, 2 do h[args[i]] = tostring(args[i + 1]) end
+    return #args / 2
+  elseif cmd == 'HINCRBY' then
+    local h = hash(key)
+    h[args[1]] = tostring((tonumber(h[args[1]]) or 0) + args[2])
+    return tonumber(h[args[1]])
+  end
+  error('unsupported command ' .. cmd)
+end }
+";
+
+    pub struct ScriptStub(Lua);
+    impl ScriptStub {
+        pub fn new() -> Self {
+            let lua = Lua::new();
+            lua.load(STUB).exec().unwrap();
+            Self(lua)
+        }
+        pub fn run<R: for<'lua> FromLuaMulti<'lua>>(
+            &self,
+            script: &str,
+            keys: &[&str],
+            args: &[String],
+        ) -> R {
+            let globals = self.0.globals();
+            globals.set("KEYS", keys.to_vec()).unwrap();
+            globals.set("ARGV", args.to_vec()).unwrap();
+            // a returned table is spread into values , false (redis nil) into nil
+            let script = format!(
+                "local r = (function()\n{}\nend)()
+if type(r) ~= 'table' then return r end
+local n = #r
+for i = 1, n do if r[i] == false then r[i] = nil end end
+return unpack(r, 1, n)",
+                script
+            );
+            self.0.load(&script).eval().unwrap()
+        }
+        // raw value of a key, or of a hash field
+        pub fn get(&self, key: &str, field: Option<&str>) -> Option<Vec<u8>> {
+            let store: mlua::Table = self.0.globals().get("store").unwrap();
+            let value: Option<mlua::String> = match field {
+                None => store.get(key).unwrap(),
+                Some(field) => store
+                    .get::<_, Option<mlua::Table>>(key)
+                    .unwrap()
+                    .and_then(|h| h.get(field).unwrap()),
+            };
+            value.map(|v| v.as_bytes().to_vec())
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use chrono::Utc;
+use futures::{StreamExt, TryStreamExt};
 use scylla::prepared_statement::PreparedStatement;
 use scylla::transport::query_result::FirstRowTypedError;
 use scylla::{FromRow, FromUserType, IntoUserType, Session, SessionBuilder};
@@ -6,69 +7,191 @@ use serde::Serialize;
 use uuid::Uuid;
 
 use super::err_models::VpError;
+use super::metrics_models::scylla_timed;
+use super::migration_models::{latest_version, SchemaConfig, MIGRATIONS};
 use super::p_models::UpdatePixel;
 
+// tables cleared on canvas reset , canvas pixels are kept per generation
+const RESET_TABLES: [&str; 1] = ["player"];
+// older canvas tables , copied into generation 0 of canvas_pixels
+const LEGACY_TABLES: [&str; 1] = ["canvas"];
+// concurrent inserts while migrating legacy canvas
+const MIGRATE_CONCURRENCY: usize = 64;
+
 //ScyllaBuilder
 pub struct ScyllaBuilder {
     session: Session,
-    dim_mid: u32,
+    tile_size: u32,
+    schema: SchemaConfig,
 }
 impl ScyllaBuilder {
-    pub async fn try_init(scylla_url: &str, canvas_dim: u32) -> Result<Self, VpError> {
+    // tile_size must stay the same once pixels are stored
+    pub async fn try_init(
+        scylla_url: &str,
+        tile_size: u32,
+        schema: SchemaConfig,
+    ) -> Result<Self, VpError> {
         let session = SessionBuilder::new().known_node(scylla_url).build().await?;
-        let dim_mid = canvas_dim / 2;
-        Ok(Self { session, dim_mid })
+        Ok(Self {
+            session,
+            tile_size: tile_size.max(1),
+            schema,
+        })
     }
-    async fn init_table(&self) -> Result<(), VpError> {
-        //Store Pixel Update of Each User
-        //->used to check cooldown
-        self.session.query("CREATE KEYSPACE IF NOT EXISTS vplace WITH REPLICATION = {'class' : 'NetworkTopologyStrategy', 'replication_factor' : 1}", &[]).await?;
-        //table to store User's last pixel placement
+    // highest applied migration , 0 if schema was never migrated
+    async fn schema_version(&self) -> Result<i32, VpError> {
+        let table = self
+            .session
+            .query(
+                "SELECT table_name FROM system_schema.tables WHERE keyspace_name = ? AND table_name = 'schema_version'",
+                (self.schema.keyspace.as_str(),),
+            )
+            .await?;
+        if table.rows_num().unwrap_or_default() == 0 {
+            return Ok(0);
+        }
+        let rows = self
+            .session
+            .query(
+                self.schema
+                    .statement("SELECT version FROM {ks}.schema_version"),
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .rows_typed_or_empty::<(i32,)>()
+            .filter_map(|row| row.ok())
+            .map(|(version,)| version)
+            .max()
+            .unwrap_or_default())
+    }
+    // record tile size on first use , fails if pixels were stored with another one
+    async fn check_tile_size(&self) -> Result<(), VpError> {
         self.session
-        .query("CREATE TABLE IF NOT EXISTS vplace.player (id uuid,uname text,x int,y int,color int,last_placed timestamp,PRIMARY KEY (id))", &[])
-        .await?;
-
-        //Store All Pixel data
-        // UDT to store pixel_data
-        self.session.query("CREATE TYPE IF NOT EXISTS vplace.pixel_data (uname text,color int,last_placed timestamp)",&[]).await?;
-        //table to store all pixel update data in canvas
-        // Divide the canvas into 4 parts
-        //       ---------------
-        //       |      |      |
-        //       |   1  |  2   |
-        //       |------|------|
-        //       |   3  |  4   |
-        //       |      |      |
-        //       --------------
-        // each part is row with pixel details as column of the form (x,y):pixel_data
-        // where pixel_data is UDT defined above : ) .
-        self.session.query("CREATE TABLE IF NOT EXISTS vplace.canvas ( canvas_part text,x int ,y int,data frozen<pixel_data>,PRIMARY KEY (canvas_part,x,y))",&[]).await?;
-        Ok(())
+            .query(
+                self.schema.statement(
+                    "INSERT INTO {ks}.schema_config (name, value) VALUES ('tile_size', ?) IF NOT EXISTS",
+                ),
+                (self.tile_size.to_string(),),
+            )
+            .await?;
+        let stored = self
+            .session
+            .query(
+                self.schema
+                    .statement("SELECT value FROM {ks}.schema_config WHERE name = 'tile_size'"),
+                &[],
+            )
+            .await?
+            .first_row_typed::<(String,)>()
+            .map_err(VpError::ScyllaTypeErr)?
+            .0;
+        match stored.parse::<u32>() {
+            Ok(stored) if stored == self.tile_size => Ok(()),
+            Ok(stored) => Err(VpError::TileSizeMismatch(stored, self.tile_size)),
+            Err(_) => Err(VpError::TileSizeMismatch(0, self.tile_size)),
+        }
+    }
+    // apply pending migrations , returns schema version
+    // replication of an existing keyspace is changed only with `alter_replication`
+    pub async fn migrate(&self, alter_replication: bool) -> Result<i32, VpError> {
+        let replication = &self.schema.replication;
+        self.session
+            .query(
+                format!(
+                    "CREATE KEYSPACE IF NOT EXISTS {} WITH REPLICATION = {}",
+                    self.schema.keyspace, replication
+                ),
+                &[],
+            )
+            .await?;
+        // keyspace may predate configured replication , run repair after altering it
+        if alter_replication {
+            tracing::warn!(
+                "Altering replication of keyspace {} to {}",
+                self.schema.keyspace,
+                replication
+            );
+            self.session
+                .query(
+                    format!(
+                        "ALTER KEYSPACE {} WITH REPLICATION = {}",
+                        self.schema.keyspace, replication
+                    ),
+                    &[],
+                )
+                .await?;
+        }
+        self.session
+            .query(
+                self.schema.statement("CREATE TABLE IF NOT EXISTS {ks}.schema_version (version int,name text,applied_at timestamp,PRIMARY KEY (version))"),
+                &[],
+            )
+            .await?;
+        let current = self.schema_version().await?;
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            tracing::info!(
+                "Applying migration {} : {}",
+                migration.version,
+                migration.name
+            );
+            for statement in migration.statements {
+                self.session
+                    .query(self.schema.statement(statement), &[])
+                    .await?;
+            }
+            self.session
+                .query(
+                    self.schema.statement(
+                        "INSERT INTO {ks}.schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+                    ),
+                    (
+                        migration.version,
+                        migration.name,
+                        Utc::now().timestamp_millis(),
+                    ),
+                )
+                .await?;
+        }
+        self.check_tile_size().await?;
+        Ok(latest_version().max(current))
     }
 
+    // fails if schema is behind this build, run `v-place migrate` first
+    // or if SCYLLA_TILE_SIZE changed since pixels were stored
     pub async fn try_build(self) -> Result<ScyllaManager, VpError> {
-        self.init_table().await?;
-        let insert_user=self.session.prepare("INSERT INTO vplace.player (id, uname, x, y, color, last_placed) VALUES (?, ?, ?, ?, ?, ?)").await?;
+        let version = self.schema_version().await?;
+        if version < latest_version() {
+            return Err(VpError::SchemaOutdated(version, latest_version()));
+        }
+        self.check_tile_size().await?;
+        let insert_user = self.session.prepare(self.schema.statement("INSERT INTO {ks}.player (id, uname, x, y, color, last_placed) VALUES (?, ?, ?, ?, ?, ?)")).await?;
         let get_user = self
             .session
-            .prepare("SELECT id, uname, x, y, color, last_placed FROM vplace.player WHERE id = ?")
-            .await?;
-        let insert_pixel = self
-            .session
-            .prepare("INSERT INTO vplace.canvas (canvas_part,x,y,data) VALUES (?, ?, ?, ?)")
+            .prepare(self.schema.statement(
+                "SELECT id, uname, x, y, color, last_placed FROM {ks}.player WHERE id = ?",
+            ))
             .await?;
+        let insert_pixel =
+            self.session
+                .prepare(self.schema.statement(
+                    "INSERT INTO {ks}.canvas_pixels (generation,tx,ty,x,y,data) VALUES (?, ?, ?, ?, ?, ?)",
+                ))
+                .await?;
         let get_pixel = self
             .session
-            .prepare("SELECT data FROM vplace.canvas WHERE canvas_part = ? AND x=? AND y=?")
+            .prepare(self.schema.statement(
+                "SELECT data FROM {ks}.canvas_pixels WHERE generation = ? AND tx = ? AND ty = ? AND x=? AND y=?",
+            ))
             .await?;
         Ok(ScyllaManager {
             session: self.session,
-            dim_mid: self.dim_mid,
+            tile_size: self.tile_size,
+            schema: self.schema,
             insert_user,
             get_user,
             insert_pixel,
             get_pixel,
-            canvas_part: ["v_part1", "v_part2", "v_part3", "v_part4"],
         })
     }
 }
@@ -76,16 +199,24 @@ impl ScyllaBuilder {
 //ScyllaDb Manager
 pub struct ScyllaManager {
     session: Session,
-    dim_mid: u32,
+    tile_size: u32,
+    schema: SchemaConfig,
     insert_user: PreparedStatement,
     get_user: PreparedStatement,
     insert_pixel: PreparedStatement,
     get_pixel: PreparedStatement,
-    canvas_part: [&'static str; 4],
 }
 impl ScyllaManager {
+    // partition key (tx,ty) of tile containing pixel (x,y)
+    fn partition(&self, x: u32, y: u32) -> Result<(i32, i32), VpError> {
+        Ok((
+            i32::try_from(x / self.tile_size)?,
+            i32::try_from(y / self.tile_size)?,
+        ))
+    }
+    #[tracing::instrument(name = "scylla.get_user", skip_all)]
     pub async fn get_user(&self, uid: &Uuid) -> Result<UserDetails, VpError> {
-        let rows = self.session.execute(&self.get_user, (uid,)).await?;
+        let rows = scylla_timed("get_user", self.session.execute(&self.get_user, (uid,))).await?;
         let res = rows.first_row_typed::<UserDetails>();
         match res {
             Ok(res) => Ok(res),
@@ -93,10 +224,11 @@ impl ScyllaManager {
             Err(e) => Err(VpError::ScyllaTypeErr(e)),
         }
     }
-    pub async fn update_db(&self, req: &UpdatePixel) -> Result<(), VpError> {
+    #[tracing::instrument(name = "scylla.update_db", skip_all)]
+    pub async fn update_db(&self, req: &UpdatePixel, generation: u64) -> Result<(), VpError> {
         let (ix, iy) = (i32::try_from(req.loc.0)?, i32::try_from(req.loc.1)?);
-        // infallible :)
-        let color = i32::try_from(req.color).unwrap();
+        let generation = i64::try_from(generation)?;
+        let color = i32::from(req.color);
         //already checked in handler
         let last_placed = Utc::now().timestamp();
 
@@ -107,37 +239,54 @@ impl ScyllaManager {
         );
 
         // add  pixel update
-        let pindex = match (req.loc.0 <= self.dim_mid, req.loc.1 <= self.dim_mid) {
-            (true, true) => 0,
-            (true, false) => 1,
-            (false, true) => 2,
-            (false, false) => 3,
-        };
+        let (tx, ty) = self.partition(req.loc.0, req.loc.1)?;
         let pixel_data = PixelData {
             uname: req.uname.to_string(),
             color,
             last_placed,
         };
-        let pixel_update = self.session.execute(
-            &self.insert_pixel,
-            (self.canvas_part[pindex], ix, iy, pixel_data),
-        );
-        tokio::try_join!(user_update, pixel_update)?;
+        let pixel_update = self
+            .session
+            .execute(&self.insert_pixel, (generation, tx, ty, ix, iy, pixel_data));
+        scylla_timed("update_db", async {
+            tokio::try_join!(user_update, pixel_update)
+        })
+        .await?;
         Ok(())
     }
-    pub async fn get_pixel(&self, x: u32, y: u32) -> Result<PixelData, VpError> {
+    // only the user's last placement , keeps cooldown of pixel updates that aren't written
+    #[tracing::instrument(name = "scylla.update_user", skip_all)]
+    pub async fn update_user(&self, req: &UpdatePixel) -> Result<(), VpError> {
+        let (ix, iy) = (i32::try_from(req.loc.0)?, i32::try_from(req.loc.1)?);
+        let color = i32::from(req.color);
+        scylla_timed(
+            "update_user",
+            self.session.execute(
+                &self.insert_user,
+                (
+                    req.uid,
+                    req.uname.as_str(),
+                    ix,
+                    iy,
+                    color,
+                    Utc::now().timestamp(),
+                ),
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+    pub async fn get_pixel(&self, x: u32, y: u32, generation: u64) -> Result<PixelData, VpError> {
+        let generation = i64::try_from(generation)?;
         let ix = i32::try_from(x)?;
         let iy = i32::try_from(y)?;
-        let pindex = match (x <= self.dim_mid, y <= self.dim_mid) {
-            (true, true) => 0,
-            (true, false) => 1,
-            (false, true) => 2,
-            (false, false) => 3,
-        };
-        let rows = self
-            .session
-            .execute(&self.get_pixel, (self.canvas_part[pindex], ix, iy))
-            .await?;
+        let (tx, ty) = self.partition(x, y)?;
+        let rows = scylla_timed(
+            "get_pixel",
+            self.session
+                .execute(&self.get_pixel, (generation, tx, ty, ix, iy)),
+        )
+        .await?;
         let res = rows.first_row_typed::<(PixelData,)>();
         match res {
             Ok(res) => Ok(res.0),
@@ -145,15 +294,82 @@ impl ScyllaManager {
             Err(e) => Err(VpError::ScyllaTypeErr(e)),
         }
     }
-    pub async fn reset_db(&self) -> Result<(), VpError> {
-        self.session
-            .query("TRUNCATE TABLE vplace.player", &[])
-            .await?;
-        self.session
-            .query("TRUNCATE TABLE vplace.pixel_data", &[])
-            .await?;
+    // cheap query used by readiness check
+    pub async fn ping(&self) -> Result<(), VpError> {
+        scylla_timed(
+            "ping",
+            self.session.query("SELECT now() FROM system.local", &[]),
+        )
+        .await?;
         Ok(())
     }
+    // copy pixels from older canvas table (4 part {ks}.canvas)
+    // into generation 0 of {ks}.canvas_pixels
+    // rows are written with last_placed as write time, so newer live updates win
+    // and the migration is safe to rerun : )
+    pub async fn migrate_legacy_canvas(&self) -> Result<usize, VpError> {
+        let insert = self
+            .session
+            .prepare(self.schema.statement("INSERT INTO {ks}.canvas_pixels (generation,tx,ty,x,y,data) VALUES (0, ?, ?, ?, ?, ?) USING TIMESTAMP ?"))
+            .await?;
+        let insert = &insert;
+        let mut copied = 0;
+        for table in LEGACY_TABLES {
+            let legacy = self
+                .session
+                .query(
+                    "SELECT table_name FROM system_schema.tables WHERE keyspace_name = ? AND table_name = ?",
+                    (self.schema.keyspace.as_str(), table),
+                )
+                .await?;
+            if legacy.rows_num().unwrap_or_default() == 0 {
+                tracing::info!("No legacy table {} found, nothing to migrate", table);
+                continue;
+            }
+            let count = self
+                .session
+                .query_iter(
+                    format!("SELECT x, y, data FROM {}.{}", self.schema.keyspace, table),
+                    &[],
+                )
+                .await?
+                .into_typed::<(i32, i32, PixelData)>()
+                .map_err(VpError::from)
+                .map(|row| async move {
+                    let (x, y, data) = row?;
+                    let (tx, ty) = self.partition(u32::try_from(x)?, u32::try_from(y)?)?;
+                    // write time in micros
+                    let timestamp = data.last_placed * 1_000_000;
+                    self.session
+                        .execute(insert, (tx, ty, x, y, data, timestamp))
+                        .await?;
+                    Ok::<_, VpError>(())
+                })
+                .buffer_unordered(MIGRATE_CONCURRENCY)
+                .try_fold(0, |count, _| async move { Ok(count + 1) })
+                .await?;
+            tracing::info!(
+                "Migrated {} pixels, {}.{} can be dropped once verified",
+                count,
+                self.schema.keyspace,
+                table
+            );
+            copied += count;
+        }
+        Ok(copied)
+    }
+    // truncate player table , returns truncated tables
+    pub async fn reset_db(&self) -> Result<Vec<String>, VpError> {
+        let mut truncated = Vec::with_capacity(RESET_TABLES.len());
+        for table in RESET_TABLES {
+            let table = format!("{}.{}", self.schema.keyspace, table);
+            self.session
+                .query(format!("TRUNCATE TABLE {}", table), &[])
+                .await?;
+            truncated.push(table);
+        }
+        Ok(truncated)
+    }
 }
 
 //ScyllaDb RowData
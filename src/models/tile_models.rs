@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+
+use super::err_models::VpError;
+
+// default palette of the 16 canvas colors (rgb hex)
+const DEFAULT_PALETTE: &str = "FFFFFF,E4E4E4,888888,222222,FFA7D1,E50000,E59500,A06A42,E5D900,94E044,02BE01,00D3DD,0083C7,0000EA,CF6EE4,820080";
+
+// Canvas is split into size x size tiles , edge tiles may be smaller
+pub struct TileConfig {
+    pub size: u32,
+    // rgb of each color index, used for png tiles
+    pub palette: Vec<u8>,
+}
+impl TileConfig {
+    pub fn new(size: u32, palette: &str) -> Self {
+        let parse = |palette: &str| {
+            palette
+                .split(',')
+                .map(|c| u32::from_str_radix(c.trim().trim_start_matches('#'), 16).ok())
+                .collect::<Option<Vec<u32>>>()
+                .filter(|colors| colors.len() == 16)
+        };
+        let colors = parse(palette).unwrap_or_else(|| parse(DEFAULT_PALETTE).unwrap_or_default());
+        Self {
+            size: size.max(1),
+            palette: colors
+                .iter()
+                .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+                .collect(),
+        }
+    }
+    // no. of tiles along each axis
+    pub fn count(&self, dim: u32) -> u32 {
+        dim.div_ceil(self.size)
+    }
+    // index of tile containing (x,y)
+    pub fn index(&self, dim: u32, loc: (u32, u32)) -> usize {
+        let count = self.count(dim) as usize;
+        (loc.0 / self.size) as usize * count + (loc.1 / self.size) as usize
+    }
+    // (x,y,width,height) of tile (tx,ty)
+    pub fn bounds(&self, dim: u32, tx: u32, ty: u32) -> Option<(u32, u32, u32, u32)> {
+        let (x, y) = (tx.checked_mul(self.size)?, ty.checked_mul(self.size)?);
+        if x >= dim || y >= dim {
+            return None;
+        }
+        Some((x, y, self.size.min(dim - x), self.size.min(dim - y)))
+    }
+}
+
+pub struct Tile {
+    pub tx: u32,
+    pub ty: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub version: u64,
+    // u4 pixels laid out like the canvas bitfield : offset = x*height+y , high nibble first
+    pub raw: Vec<u8>,
+}
+impl Tile {
+    // cut tile (tx,ty) out of canvas bitfield
+    pub fn extract(
+        canvas: &[u8],
+        dim: u32,
+        conf: &TileConfig,
+        tile: (u32, u32),
+        version: u64,
+    ) -> Option<Self> {
+        let (x, y, width, height) = conf.bounds(dim, tile.0, tile.1)?;
+        let color = |px: u32, py: u32| {
+            let offset = (px * dim + py) as usize;
+            canvas.get(offset / 2).map_or(0, |b| {
+                if offset.is_multiple_of(2) {
+                    b >> 4
+                } else {
+                    b & 0x0F
+                }
+            })
+        };
+        let mut raw = vec![0u8; (width * height).div_ceil(2) as usize];
+        for lx in 0..width {
+            for ly in 0..height {
+                let offset = (lx * height + ly) as usize;
+                let c = color(x + lx, y + ly);
+                raw[offset / 2] |= if offset.is_multiple_of(2) { c << 4 } else { c };
+            }
+        }
+        Some(Self {
+            tx: tile.0,
+            ty: tile.1,
+            x,
+            y,
+            width,
+            height,
+            version,
+            raw,
+        })
+    }
+    fn color(&self, lx: u32, ly: u32) -> u8 {
+        let offset = (lx * self.height + ly) as usize;
+        let b = self.raw[offset / 2];
+        if offset.is_multiple_of(2) {
+            b >> 4
+        } else {
+            b & 0x0F
+        }
+    }
+    // 4bit indexed png , image rows are y : )
+    pub fn png(&self, palette: &[u8]) -> Result<Vec<u8>, VpError> {
+        let mut data = Vec::with_capacity((self.width.div_ceil(2) * self.height) as usize);
+        for ly in 0..self.height {
+            for lx in (0..self.width).step_by(2) {
+                let hi = self.color(lx, ly);
+                let lo = if lx + 1 < self.width {
+                    self.color(lx + 1, ly)
+                } else {
+                    0
+                };
+                data.push(hi << 4 | lo);
+            }
+        }
+        let mut buf = Vec::new();
+        let mut encoder = png::Encoder::new(&mut buf, self.width, self.height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Four);
+        encoder.set_palette(palette);
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(buf)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TileFormat {
+    Raw,
+    Base64,
+    Png,
+}
+
+#[derive(Deserialize)]
+pub struct TileQuery {
+    // overrides Accept header
+    pub format: Option<TileFormat>,
+}
+
+#[derive(Serialize)]
+pub struct TileResponse<'a> {
+    pub tx: u32,
+    pub ty: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub version: u64,
+    pub tile: &'a str,
+}
+
+// versions of all tiles , index = tx*tiles+ty
+// a tile version is the last change of the tile seen by the serving instance,
+// or the version its canvas was loaded at, so it may be newer than the actual change.
+// tiles fetched at canvas `version` (or later) only need a refetch once their version exceeds it
+#[derive(Serialize)]
+pub struct TileIndex {
+    pub tile_size: u32,
+    pub tiles: u32,
+    // canvas version of the index
+    pub version: u64,
+    pub versions: Vec<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 5x5 canvas of 2x2 tiles , edge tiles are 1 pixel wide/high
+    const DIM: u32 = 5;
+
+    fn conf() -> TileConfig {
+        TileConfig::new(2, "")
+    }
+
+    fn color(x: u32, y: u32) -> u8 {
+        ((x * DIM + y) % 16) as u8
+    }
+
+    fn canvas() -> Vec<u8> {
+        let mut canvas = vec![0u8; (DIM * DIM).div_ceil(2) as usize];
+        for x in 0..DIM {
+            for y in 0..DIM {
+                let offset = (x * DIM + y) as usize;
+                canvas[offset / 2] |= if offset.is_multiple_of(2) {
+                    color(x, y) << 4
+                } else {
+                    color(x, y)
+                };
+            }
+        }
+        canvas
+    }
+
+    fn extract(tx: u32, ty: u32) -> Option<Tile> {
+        Tile::extract(&canvas(), DIM, &conf(), (tx, ty), 7)
+    }
+
+    // (width, height, rows of packed u4 pixels)
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf).unwrap();
+        assert_eq!(frame.color_type, png::ColorType::Indexed);
+        assert_eq!(frame.bit_depth, png::BitDepth::Four);
+        buf.truncate(frame.buffer_size());
+        (frame.width, frame.height, buf)
+    }
+
+    #[test]
+    fn default_palette() {
+        let conf = TileConfig::new(0, "not,a,palette");
+        assert_eq!(conf.size, 1);
+        assert_eq!(conf.palette.len(), 48);
+        assert_eq!(conf.palette[..3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(conf.palette[45..], [0x82, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn edge_tiles_are_smaller() {
+        let conf = conf();
+        assert_eq!(conf.count(DIM), 3);
+        assert_eq!(conf.bounds(DIM, 0, 0), Some((0, 0, 2, 2)));
+        assert_eq!(conf.bounds(DIM, 2, 1), Some((4, 2, 1, 2)));
+        assert_eq!(conf.bounds(DIM, 2, 2), Some((4, 4, 1, 1)));
+        assert_eq!(conf.bounds(DIM, 3, 0), None);
+        assert_eq!(conf.bounds(DIM, u32::MAX, 0), None);
+        assert_eq!(conf.index(DIM, (4, 3)), 7);
+    }
+
+    #[test]
+    fn extract_full_tile() {
+        let tile = extract(1, 0).unwrap();
+        assert_eq!((tile.x, tile.y, tile.width, tile.height), (2, 0, 2, 2));
+        assert_eq!(tile.version, 7);
+        // (2,0)=10 (2,1)=11 (3,0)=15 (3,1)=0
+        assert_eq!(tile.raw, vec![0xAB, 0xF0]);
+    }
+
+    #[test]
+    fn extract_edge_tiles() {
+        // (4,2)=6 (4,3)=7
+        assert_eq!(extract(2, 1).unwrap().raw, vec![0x67]);
+        // (2,4)=14 (3,4)=3
+        assert_eq!(extract(1, 2).unwrap().raw, vec![0xE3]);
+        // (4,4)=8
+        assert_eq!(extract(2, 2).unwrap().raw, vec![0x80]);
+        assert!(extract(0, 3).is_none());
+    }
+
+    #[test]
+    fn png_rows_are_y() {
+        let conf = conf();
+        let (width, height, data) = decode(&extract(1, 0).unwrap().png(&conf.palette).unwrap());
+        assert_eq!((width, height), (2, 2));
+        // row y=0 : (2,0) (3,0) , row y=1 : (2,1) (3,1)
+        assert_eq!(data, vec![0xAF, 0xB0]);
+    }
+
+    #[test]
+    fn png_edge_tiles() {
+        let conf = conf();
+        let (width, height, data) = decode(&extract(2, 1).unwrap().png(&conf.palette).unwrap());
+        assert_eq!((width, height), (1, 2));
+        assert_eq!(data, vec![0x60, 0x70]);
+        let (width, height, data) = decode(&extract(1, 2).unwrap().png(&conf.palette).unwrap());
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(data, vec![0xE3]);
+        let (width, height, data) = decode(&extract(2, 2).unwrap().png(&conf.palette).unwrap());
+        assert_eq!((width, height), (1, 1));
+        assert_eq!(data, vec![0x80]);
+    }
+}
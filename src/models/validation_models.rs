@@ -0,0 +1,201 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::err_models::VpError;
+use super::p_models::UpdatePixel;
+
+// colors are 4bit palette indexes : )
+pub const MAX_COLOR: u8 = 15;
+
+// Username constraints
+pub struct UnameRules {
+    pub min_len: usize,
+    pub max_len: usize,
+    // lowercase words not allowed anywhere in username
+    pub blocklist: Vec<String>,
+}
+impl UnameRules {
+    pub fn new(min_len: usize, max_len: usize, blocklist: &str) -> Self {
+        Self {
+            min_len,
+            max_len,
+            blocklist: blocklist
+                .split(',')
+                .map(|w| w.trim().to_lowercase())
+                .filter(|w| !w.is_empty())
+                .collect(),
+        }
+    }
+    fn check(&self, uname: &str) -> Option<FieldError> {
+        let len = uname.chars().count();
+        if len < self.min_len || len > self.max_len {
+            return Some(FieldError::new(
+                "uname",
+                "length",
+                format!("must be {}-{} characters", self.min_len, self.max_len),
+            ));
+        }
+        if !uname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Some(FieldError::new(
+                "uname",
+                "charset",
+                "only letters, digits, '_' and '-' are allowed".to_string(),
+            ));
+        }
+        // separators can't be used to sneak past the filter
+        let folded: String = uname
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>()
+            .to_lowercase();
+        if self.blocklist.iter().any(|w| folded.contains(w.as_str())) {
+            return Some(FieldError::new(
+                "uname",
+                "profanity",
+                "username is not allowed".to_string(),
+            ));
+        }
+        None
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+impl FieldError {
+    pub fn new(field: &'static str, code: &'static str, message: String) -> Self {
+        Self {
+            field,
+            code,
+            message,
+        }
+    }
+}
+
+impl UpdatePixel {
+    // checked before any redis/scylla work, returns all field errors at once
+    pub fn validate(&self, canvas_dim: u32, rules: &UnameRules) -> Result<(), VpError> {
+        let mut errs = Vec::new();
+        if self.uid.eq(&Uuid::nil()) {
+            errs.push(FieldError::new(
+                "uid",
+                "nil",
+                "uid can't be nil".to_string(),
+            ));
+        }
+        if let Some(err) = rules.check(&self.uname) {
+            errs.push(err);
+        }
+        if self.loc.0 >= canvas_dim || self.loc.1 >= canvas_dim {
+            errs.push(FieldError::new(
+                "loc",
+                "out_of_bounds",
+                format!("(x,y) must be < {}", canvas_dim),
+            ));
+        }
+        if self.color > MAX_COLOR {
+            errs.push(FieldError::new(
+                "color",
+                "out_of_range",
+                format!("accepted range [0,{}]", MAX_COLOR),
+            ));
+        }
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(VpError::Validation(errs))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> UnameRules {
+        UnameRules::new(3, 12, " Admin, ,mod ")
+    }
+
+    fn code(uname: &str) -> Option<&'static str> {
+        rules().check(uname).map(|e| e.code)
+    }
+
+    fn pixel(uid: Uuid, uname: &str, loc: (u32, u32), color: u8) -> UpdatePixel {
+        UpdatePixel {
+            uid,
+            uname: uname.to_string(),
+            loc,
+            color,
+            proof: None,
+        }
+    }
+
+    fn fields(res: Result<(), VpError>) -> Vec<(&'static str, &'static str)> {
+        match res {
+            Err(VpError::Validation(errs)) => errs.iter().map(|e| (e.field, e.code)).collect(),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(()) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn blocklist_is_trimmed_and_lowercased() {
+        assert_eq!(rules().blocklist, vec!["admin", "mod"]);
+    }
+
+    #[test]
+    fn uname_length_counts_chars() {
+        assert_eq!(code("ab"), Some("length"));
+        assert_eq!(code("abcdefghijklm"), Some("length"));
+        assert_eq!(code("abc"), None);
+        assert_eq!(code("abcdefghijkl"), None);
+        // multibyte chars fail on charset , not length
+        assert_eq!(code("héllo"), Some("charset"));
+    }
+
+    #[test]
+    fn uname_charset() {
+        assert_eq!(code("some_one-1"), None);
+        assert_eq!(code("some one"), Some("charset"));
+        assert_eq!(code("some.one"), Some("charset"));
+    }
+
+    #[test]
+    fn uname_blocklist_ignores_case_and_separators() {
+        assert_eq!(code("ADMIN"), Some("profanity"));
+        assert_eq!(code("the_ad-min"), Some("profanity"));
+        assert_eq!(code("m_o_d"), Some("profanity"));
+        assert_eq!(code("madmax"), None);
+    }
+
+    #[test]
+    fn valid_pixel_update() {
+        let req = pixel(Uuid::new_v4(), "someone", (9, 0), MAX_COLOR);
+        assert!(req.validate(10, &rules()).is_ok());
+    }
+
+    #[test]
+    fn all_field_errors_reported() {
+        let req = pixel(Uuid::nil(), "x", (10, 3), MAX_COLOR + 1);
+        assert_eq!(
+            fields(req.validate(10, &rules())),
+            vec![
+                ("uid", "nil"),
+                ("uname", "length"),
+                ("loc", "out_of_bounds"),
+                ("color", "out_of_range"),
+            ]
+        );
+        let req = pixel(Uuid::new_v4(), "someone", (3, 10), 0);
+        assert_eq!(
+            fields(req.validate(10, &rules())),
+            vec![("loc", "out_of_bounds")]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::models::challenge_models::{Challenge, ChallengeVerifier};
+use crate::models::err_models::VpError;
+use crate::models::p_models::{AppState, UpdatePixel};
+use crate::models::redis_models::RedisManager;
+use crate::models::scylla_models::ScyllaManager;
+
+// Challenges disabled
+pub struct NoChallenge;
+
+impl ChallengeVerifier for NoChallenge {
+    fn enabled(&self) -> bool {
+        false
+    }
+    fn issue<'a>(
+        &'a self,
+        _app_state: &'a AppState<'_>,
+        _redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<Option<Challenge>, VpError>> {
+        async { Ok(None) }.boxed_local()
+    }
+    fn verify<'a>(
+        &'a self,
+        _uid: &'a Uuid,
+        _proof: &'a str,
+        _app_state: &'a AppState<'_>,
+        _redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<bool, VpError>> {
+        async { Ok(true) }.boxed_local()
+    }
+}
+
+// deletes challenge , 1 on its first use only
+const CONSUME_CHALLENGE: &str = r"
+return redis.call('DEL', KEYS[1])
+";
+
+// Hashcash style proof-of-work, works without any external service : )
+// client finds nonce such that sha256("{challenge}:{uid}:{nonce}") has `difficulty` leading zero bits
+// proof : "{challenge}:{nonce}" , each challenge can be used only once
+pub struct PowVerifier {
+    pub difficulty: u32,
+    // seconds a challenge stays valid
+    pub ttl: usize,
+}
+
+impl PowVerifier {
+    fn leading_zeros(hash: &[u8]) -> u32 {
+        let mut zeros = 0;
+        for byte in hash {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+    // challenge of proof if its nonce does enough work for uid
+    fn solved<'p>(&self, uid: &Uuid, proof: &'p str) -> Option<&'p str> {
+        let (challenge, nonce) = proof.split_once(':')?;
+        let hash = Sha256::digest(format!("{}:{}:{}", challenge, uid, nonce));
+        (Self::leading_zeros(&hash) >= self.difficulty).then_some(challenge)
+    }
+}
+
+impl ChallengeVerifier for PowVerifier {
+    fn issue<'a>(
+        &'a self,
+        app_state: &'a AppState<'_>,
+        redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<Option<Challenge>, VpError>> {
+        async move {
+            let challenge = Uuid::new_v4().simple().to_string();
+            let mut conn = redis.clone();
+            redis::Cmd::set_ex(app_state.challenge_key(&challenge), 1, self.ttl)
+                .query_async::<_, ()>(&mut conn)
+                .await?;
+            Ok(Some(Challenge {
+                kind: "pow",
+                challenge,
+                difficulty: Some(self.difficulty),
+            }))
+        }
+        .boxed_local()
+    }
+    fn verify<'a>(
+        &'a self,
+        uid: &'a Uuid,
+        proof: &'a str,
+        app_state: &'a AppState<'_>,
+        redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<bool, VpError>> {
+        async move {
+            let Some(challenge) = self.solved(uid, proof) else {
+                return Ok(false);
+            };
+            // consume challenge
+            let mut conn = redis.clone();
+            let deleted = redis::Script::new(CONSUME_CHALLENGE)
+                .key(app_state.challenge_key(challenge))
+                .invoke_async::<_, u8>(&mut conn)
+                .await?;
+            Ok(deleted.eq(&1))
+        }
+        .boxed_local()
+    }
+}
+
+// Captcha stand-in for local testing, accepts a fixed token as proof
+pub struct MockCaptchaVerifier {
+    pub token: String,
+}
+
+impl ChallengeVerifier for MockCaptchaVerifier {
+    fn issue<'a>(
+        &'a self,
+        _app_state: &'a AppState<'_>,
+        _redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<Option<Challenge>, VpError>> {
+        async { Ok(None) }.boxed_local()
+    }
+    fn verify<'a>(
+        &'a self,
+        _uid: &'a Uuid,
+        proof: &'a str,
+        _app_state: &'a AppState<'_>,
+        _redis: &'a RedisManager,
+    ) -> LocalBoxFuture<'a, Result<bool, VpError>> {
+        async move { Ok(proof.eq(&self.token)) }.boxed_local()
+    }
+}
+
+// new users and users flagged as suspicious should solve a challenge
+async fn needs_challenge(
+    uid: &Uuid,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+) -> Result<bool, VpError> {
+    let mut conn = redis.clone();
+    let flagged = redis::Cmd::sismember(app_state.flagged_key(), uid.to_string())
+        .query_async::<_, bool>(&mut conn)
+        .await?;
+    if flagged {
+        return Ok(true);
+    }
+    match scylla.get_user(uid).await {
+        Ok(_) => Ok(false),
+        Err(VpError::InvalidUser) => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn check_challenge(
+    u_req: &UpdatePixel,
+    verifier: &dyn ChallengeVerifier,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+) -> Result<(), VpError> {
+    if !verifier.enabled() || !needs_challenge(&u_req.uid, app_state, redis, scylla).await? {
+        return Ok(());
+    }
+    match &u_req.proof {
+        Some(proof) if verifier.verify(&u_req.uid, proof, app_state, redis).await? => Ok(()),
+        Some(_) => Err(VpError::InvalidProof),
+        None => Err(VpError::ChallengeRequired),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::redis_models::script_stub::ScriptStub;
+
+    fn pow(difficulty: u32) -> PowVerifier {
+        PowVerifier {
+            difficulty,
+            ttl: 60,
+        }
+    }
+
+    // first nonce whose hash has exactly `zeros` leading zero bits
+    fn nonce(challenge: &str, uid: &Uuid, zeros: u32) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}:{}:{}", challenge, uid, nonce));
+                PowVerifier::leading_zeros(&hash) == zeros
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn leading_zero_bits() {
+        assert_eq!(PowVerifier::leading_zeros(&[0xFF]), 0);
+        assert_eq!(PowVerifier::leading_zeros(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(PowVerifier::leading_zeros(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn proof_meets_difficulty() {
+        let uid = Uuid::new_v4();
+        let proof = format!("abc:{}", nonce("abc", &uid, 8));
+        assert_eq!(pow(8).solved(&uid, &proof), Some("abc"));
+        assert_eq!(pow(6).solved(&uid, &proof), Some("abc"));
+        assert_eq!(pow(9).solved(&uid, &proof), None);
+        let weak = format!("abc:{}", nonce("abc", &uid, 7));
+        assert_eq!(pow(8).solved(&uid, &weak), None);
+    }
+
+    #[test]
+    fn wrong_nonce_rejected() {
+        let uid = Uuid::new_v4();
+        let solution = nonce("abc", &uid, 10);
+        // nonce is bound to challenge and uid
+        assert_eq!(
+            pow(10).solved(&Uuid::new_v4(), &format!("abc:{}", solution)),
+            None
+        );
+        assert_eq!(pow(10).solved(&uid, &format!("abd:{}", solution)), None);
+        assert_eq!(pow(10).solved(&uid, &format!("abc:{}0", solution)), None);
+        assert_eq!(pow(0).solved(&uid, "abc"), None);
+    }
+
+    #[test]
+    fn challenge_single_use() {
+        let redis = ScriptStub::new();
+        let key = "canvas:challenge:abc";
+        redis.run::<()>("redis.call('SET', KEYS[1], 1)", &[key], &[]);
+        assert_eq!(redis.run::<u8>(CONSUME_CHALLENGE, &[key], &[]), 1);
+        assert_eq!(redis.run::<u8>(CONSUME_CHALLENGE, &[key], &[]), 0);
+        // never issued
+        assert_eq!(
+            redis.run::<u8>(CONSUME_CHALLENGE, &["canvas:challenge:xyz"], &[]),
+            0
+        );
+    }
+}
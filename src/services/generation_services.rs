@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use crate::models::err_models::VpError;
+use crate::models::generation_models::{Generation, GenerationStats};
+use crate::models::metrics_models::redis_timed;
+use crate::models::p_models::AppState;
+use crate::models::redis_models::RedisManager;
+
+// final (or current) canvas and stats of a generation
+pub async fn get_generation(
+    generation: u64,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<Generation, VpError> {
+    let mut conn = redis.clone();
+    let (current, canvas, stats, painters) = redis_timed(
+        "get_generation",
+        redis::pipe()
+            .atomic()
+            .get(app_state.generation_key())
+            .get(app_state.canvas_key(generation))
+            .hgetall(app_state.generation_stats_key(generation))
+            .pfcount(app_state.generation_painters_key(generation))
+            .query_async::<_, (Option<u64>, Option<Vec<u8>>, HashMap<String, i64>, u64)>(&mut conn),
+    )
+    .await?;
+    let current = current.unwrap_or_default();
+    let canvas = match canvas {
+        Some(canvas) if generation <= current => canvas,
+        _ => Err(VpError::GenerationNotFound(generation))?,
+    };
+    Ok(Generation {
+        generation,
+        current: generation == current,
+        started_at: stats.get("started_at").copied(),
+        ended_at: stats.get("ended_at").copied(),
+        final_version: stats
+            .get("final_version")
+            .and_then(|v| u64::try_from(*v).ok()),
+        stats: GenerationStats {
+            placements: stats
+                .get("placements")
+                .map_or(0, |p| u64::try_from(*p).unwrap_or_default()),
+            painters,
+        },
+        canvas,
+    })
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix::Addr;
+
+use crate::models::err_models::VpError;
+use crate::models::health_models::{DepStatus, Readiness};
+use crate::models::p_models::{AppState, VpCount, VpSrv};
+use crate::models::redis_models::RedisManager;
+use crate::models::scylla_models::ScyllaManager;
+use crate::services::p_services::current_generation;
+
+// a dependency slower than this is not ready
+const CHECK_TIMEOUT_MS: u64 = 2000;
+
+async fn check<E: Display>(fut: impl Future<Output = Result<(), E>>) -> DepStatus {
+    let start = Instant::now();
+    let res = tokio::time::timeout(Duration::from_millis(CHECK_TIMEOUT_MS), fut).await;
+    let latency_ms = start.elapsed().as_millis();
+    let error = match res {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("timed out after {}ms", CHECK_TIMEOUT_MS)),
+    };
+    DepStatus {
+        ok: error.is_none(),
+        latency_ms,
+        error,
+    }
+}
+
+async fn check_redis(redis: &RedisManager) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    redis::cmd("PING").query_async::<_, ()>(&mut conn).await?;
+    Ok(())
+}
+
+async fn check_canvas(app_state: &AppState<'_>, redis: &RedisManager) -> Result<(), String> {
+    let mut conn = redis.clone();
+    let generation = current_generation(app_state, redis)
+        .await
+        .map_err(|e| e.to_string())?;
+    let canvas_key = app_state.canvas_key(generation);
+    let exists = redis::Cmd::exists(&canvas_key)
+        .query_async::<_, bool>(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    if exists {
+        Ok(())
+    } else {
+        Err(format!("canvas key {} not found", canvas_key))
+    }
+}
+
+async fn check_srv(pu_srv: &Addr<VpSrv<'_>>) -> Result<(), VpError> {
+    pu_srv.send(VpCount).await.map_err(VpError::MailboxErr)?;
+    Ok(())
+}
+
+// all dependencies are checked concurrently : )
+pub async fn readiness(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+) -> Readiness {
+    let (redis_status, scylla_status, canvas_status, srv_status) = tokio::join!(
+        check(check_redis(redis)),
+        check(scylla.ping()),
+        check(check_canvas(app_state, redis)),
+        check(check_srv(pu_srv)),
+    );
+    Readiness::new(BTreeMap::from([
+        ("redis", redis_status),
+        ("scylla", scylla_status),
+        ("canvas", canvas_status),
+        ("vp_srv", srv_status),
+    ]))
+}
@@ -1 +1,6 @@
+pub mod challenge_services;
+pub mod generation_services;
+pub mod health_services;
 pub mod p_services;
+pub mod raid_services;
+pub mod rate_services;
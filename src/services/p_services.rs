@@ -1,100 +1,429 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
 use actix::Addr;
+use actix_web::web;
 use chrono::Utc;
-use futures::TryFutureExt;
-use redis::Client;
+use futures::StreamExt;
+use redis::{Client, RedisError};
+use tracing::Instrument;
 use uuid::Uuid;
 
+use crate::models::cache_models::{CanvasCache, CanvasSnapshot};
+use crate::models::challenge_models::ChallengeVerifier;
 use crate::models::err_models::VpError;
-use crate::models::p_models::{AppState, PlaceUpdate, UpdatePixel, VpSrv};
+use crate::models::metrics_models::{redis_timed, METRICS};
+use crate::models::p_models::{
+    AppState, CanvasLifecycle, CanvasState, Credits, PlaceUpdate, Placement, Presence, PubEvent,
+    ResetReport, SysEvent, UpdatePixel, VpCount, VpEvent, VpSrv, WaitTime,
+};
+use crate::models::redis_models::RedisManager;
 use crate::models::scylla_models::ScyllaManager;
+use crate::models::validation_models::MAX_COLOR;
+use crate::services::challenge_services::check_challenge;
+use crate::services::raid_services::is_shadowbanned;
 
-pub async fn init_place(app_state: &AppState<'_>, redis: &Client) -> Result<(), VpError> {
-    let mut conn = redis.get_tokio_connection_manager().await?;
-    if redis::Cmd::exists(app_state.canvas_id.as_bytes())
-        .query_async::<_, u8>(&mut conn)
-        .await?
-        .eq(&0)
-    {
-        let dim: usize = app_state
-            .canvas_dim
-            .try_into()
-            .map_err(|_| VpError::InitCanvasErr)?;
-        let canvas_size: usize = (dim * dim + 1) / 2;
-        log::debug!("Canvas Bitfield size {}", canvas_size);
-        redis::Cmd::set(app_state.canvas_id.as_bytes(), vec![0u8; canvas_size])
-            .query_async(&mut conn)
-            .await?;
+// wait before resubscribing after redis pub/sub connection is lost
+const SUBSCRIBE_RETRY_SECS: u64 = 5;
+// interval between canvas lifecycle checks
+const LIFECYCLE_POLL_SECS: u64 = 1;
+// placements/sec counters are only needed for a few seconds
+const PPS_TTL_SECS: usize = 10;
+// attempts of a generation script while canvas is being reset
+const GENERATION_RETRIES: usize = 3;
+// keys scanned/deleted per command when resetting per-user state
+const RESET_BATCH: usize = 500;
+
+// Token bucket of pixel credits
+// KEYS[1] : credits key , ARGV : now, refill interval, max credits
+// returns {placed, credits, next refill (0 if bucket is full)}
+const TAKE_CREDIT: &str = r"
+local now = tonumber(ARGV[1])
+local interval = tonumber(ARGV[2])
+local max = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'credits', 'updated')
+local credits = tonumber(bucket[1]) or max
+local updated = tonumber(bucket[2]) or now
+if interval > 0 then
+  local refill = math.floor((now - updated) / interval)
+  credits = math.min(max, credits + refill)
+  updated = updated + refill * interval
+else
+  credits = max
+end
+if credits >= max then
+  updated = now
+end
+local placed = 0
+if credits > 0 then
+  credits = credits - 1
+  placed = 1
+end
+redis.call('HSET', KEYS[1], 'credits', credits, 'updated', updated)
+redis.call('EXPIRE', KEYS[1], interval * max + 60)
+local next_refill = 0
+if credits < max then
+  next_refill = updated + interval
+end
+return {placed, credits, next_refill}
+";
+
+// Give back a credit of the bucket above , never past max credits
+// KEYS[1] : credits key , ARGV : max credits
+const REFUND_CREDIT: &str = r"
+local max = tonumber(ARGV[1])
+local credits = tonumber(redis.call('HGET', KEYS[1], 'credits'))
+if credits and credits < max then
+  redis.call('HSET', KEYS[1], 'credits', credits + 1)
+end
+";
+
+// Canvas keys include the generation, scripts below get the keys of the generation
+// they are expected to run on (ARGV[1]) and check it atomically against the generation key,
+// so placements never land in an ended generation : )
+// every key is passed in KEYS , returns {current generation, result}
+// result is nil when generation moved on , see `on_generation`
+
+// KEYS : generation key, canvas, stats, legacy canvas (canvas id)
+// ARGV : generation, canvas size, now
+// returns {generation, 1}
+const INIT_CANVAS: &str = r"
+local gen = tonumber(redis.call('GET', KEYS[1])) or 0
+if gen ~= tonumber(ARGV[1]) then
+  return {gen, false}
+end
+if redis.call('EXISTS', KEYS[2]) == 0 then
+  -- older versions kept the canvas at the canvas id itself
+  if redis.call('TYPE', KEYS[4]).ok == 'string' then
+    redis.call('RENAME', KEYS[4], KEYS[2])
+  else
+    redis.call('SETRANGE', KEYS[2], tonumber(ARGV[2]) - 1, '\0')
+  end
+  redis.call('HSETNX', KEYS[3], 'started_at', ARGV[3])
+end
+return {gen, 1}
+";
+
+// KEYS : generation key, version key, canvas , ARGV : generation
+// returns {generation, {canvas, version}}
+const LOAD_CANVAS: &str = r"
+local gen = tonumber(redis.call('GET', KEYS[1])) or 0
+if gen ~= tonumber(ARGV[1]) then
+  return {gen, false}
+end
+local canvas = redis.call('GET', KEYS[3]) or ''
+local version = tonumber(redis.call('GET', KEYS[2])) or 0
+return {gen, {canvas, version}}
+";
+
+// KEYS : generation key, version key, painters key, pps key, canvas, stats, generation painters
+// ARGV : generation, offset, color, uid, now, pps ttl
+// returns {generation, version}
+const PLACE_PIXEL: &str = r"
+local gen = tonumber(redis.call('GET', KEYS[1])) or 0
+if gen ~= tonumber(ARGV[1]) then
+  return {gen, false}
+end
+redis.call('BITFIELD', KEYS[5], 'SET', 'u4', '#' .. ARGV[2], ARGV[3])
+redis.call('HINCRBY', KEYS[6], 'placements', 1)
+redis.call('PFADD', KEYS[7], ARGV[4])
+local version = redis.call('INCR', KEYS[2])
+redis.call('ZADD', KEYS[3], ARGV[5], ARGV[4])
+redis.call('INCR', KEYS[4])
+redis.call('EXPIRE', KEYS[4], ARGV[6])
+return {gen, version}
+";
+
+// KEYS : generation key, version key, painters key, stats, next canvas, next stats
+// ARGV : generation, canvas size, now
+// returns {previous generation, {previous version, version}}
+const RESET_CANVAS: &str = r"
+local gen = tonumber(redis.call('GET', KEYS[1])) or 0
+if gen ~= tonumber(ARGV[1]) then
+  return {gen, false}
+end
+redis.call('INCR', KEYS[1])
+local prev_version = tonumber(redis.call('GET', KEYS[2])) or 0
+-- reset is a canvas change too, so version moves on : )
+local version = redis.call('INCR', KEYS[2])
+redis.call('HSET', KEYS[4], 'ended_at', ARGV[3], 'final_version', prev_version)
+redis.call('DEL', KEYS[5])
+redis.call('SETRANGE', KEYS[5], tonumber(ARGV[2]) - 1, '\0')
+redis.call('HSET', KEYS[6], 'started_at', ARGV[3])
+redis.call('DEL', KEYS[3])
+return {gen, {prev_version, version}}
+";
+
+// run a generation script on keys of the last seen generation,
+// retried on keys of current generation when it moved on.
+// returns generation the script ran on and its result
+async fn on_generation<T, F, Fut>(
+    app_state: &AppState<'_>,
+    mut script: F,
+) -> Result<(u64, T), VpError>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<(u64, Option<T>), RedisError>>,
+{
+    let mut generation = app_state.generation.load(Ordering::Relaxed);
+    for _ in 0..GENERATION_RETRIES {
+        let (current, res) = script(generation).await?;
+        app_state.generation.store(current, Ordering::Relaxed);
+        if let Some(res) = res {
+            return Ok((current, res));
+        }
+        generation = current;
     }
-    Ok(())
+    Err(VpError::GenerationMoved)
 }
 
-pub async fn reset_place(
-    app_state: &AppState<'_>,
-    redis: &Client,
-    scylla: &ScyllaManager,
-) -> Result<(), VpError> {
-    let mut conn = redis.get_tokio_connection_manager().await?;
+fn canvas_size(app_state: &AppState<'_>) -> Result<usize, VpError> {
     let dim: usize = app_state
         .canvas_dim
         .try_into()
         .map_err(|_| VpError::InitCanvasErr)?;
-    let canvas_size: usize = (dim * dim + 1) / 2;
-    log::debug!("Canvas Bitfield size {}", canvas_size);
-    redis::Cmd::set(app_state.canvas_id.as_bytes(), vec![0u8; canvas_size])
-        .query_async(&mut conn)
-        .await?;
-    log::debug!("[Redis] : Canvas Reset {}", app_state.canvas_id);
-    scylla.reset_db().await?;
-    log::debug!("[SycallaDb] : vplace.player & vplace.pixel_data Reset");
+    Ok((dim * dim).div_ceil(2))
+}
+
+pub async fn init_place(app_state: &AppState<'_>, redis: &RedisManager) -> Result<(), VpError> {
+    let canvas_size = canvas_size(app_state)?;
+    tracing::debug!("Canvas Bitfield size {}", canvas_size);
+    let (generation, ()) = on_generation(app_state, |generation| {
+        let mut conn = redis.clone();
+        async move {
+            redis::Script::new(INIT_CANVAS)
+                .key(app_state.generation_key())
+                .key(app_state.canvas_key(generation))
+                .key(app_state.generation_stats_key(generation))
+                .key(app_state.canvas_id.as_bytes())
+                .arg(generation)
+                .arg(canvas_size)
+                .arg(Utc::now().timestamp())
+                .invoke_async::<_, (u64, Option<()>)>(&mut conn)
+                .await
+        }
+    })
+    .await?;
+    tracing::debug!("Canvas generation {}", generation);
     Ok(())
 }
 
+// generation new placements go to
+pub async fn current_generation(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<u64, VpError> {
+    let mut conn = redis.clone();
+    let generation = redis::Cmd::get(app_state.generation_key())
+        .query_async::<_, Option<u64>>(&mut conn)
+        .await?;
+    Ok(generation.unwrap_or_default())
+}
+
+// canvas bitfield and version read together from redis, cached for later reads
+pub async fn load_canvas(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    cache: &CanvasCache,
+) -> Result<CanvasSnapshot, VpError> {
+    let (generation, (bytes, version)) = on_generation(app_state, |generation| {
+        let mut conn = redis.clone();
+        async move {
+            redis_timed(
+                "get_canvas",
+                redis::Script::new(LOAD_CANVAS)
+                    .key(app_state.generation_key())
+                    .key(app_state.version_key())
+                    .key(app_state.canvas_key(generation))
+                    .arg(generation)
+                    .invoke_async::<_, (u64, Option<(Vec<u8>, u64)>)>(&mut conn),
+            )
+            .await
+        }
+    })
+    .await?;
+    cache.load(bytes.clone(), version, generation);
+    Ok(CanvasSnapshot::new(bytes, version))
+}
+
+// start a new generation with a blank canvas , old generation is kept
+pub async fn reset_place(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+) -> Result<ResetReport, VpError> {
+    let canvas_size = canvas_size(app_state)?;
+    let (previous_generation, (previous_version, version)) =
+        on_generation(app_state, |generation| {
+            let mut conn = redis.clone();
+            async move {
+                redis::Script::new(RESET_CANVAS)
+                    .key(app_state.generation_key())
+                    .key(app_state.version_key())
+                    .key(app_state.painters_key())
+                    .key(app_state.generation_stats_key(generation))
+                    .key(app_state.canvas_key(generation + 1))
+                    .key(app_state.generation_stats_key(generation + 1))
+                    .arg(generation)
+                    .arg(canvas_size)
+                    .arg(Utc::now().timestamp())
+                    .invoke_async::<_, (u64, Option<(u64, u64)>)>(&mut conn)
+                    .await
+            }
+        })
+        .await?;
+    let generation = previous_generation + 1;
+    tracing::debug!(
+        "[Redis] : Canvas {} Reset to generation {}",
+        app_state.canvas_id,
+        generation
+    );
+    // generation already moved on , clients are told before anything else can fail
+    broadcast_event(
+        SysEvent::CanvasReset {
+            generation,
+            version,
+        },
+        app_state,
+        redis,
+        pu_srv,
+    )
+    .await?;
+    // cooldowns start over with the new generation, and so do pixel credits
+    // and challenges of flagged users. best-effort : canvas is reset either way,
+    // users placing right at reset may get their cooldown cleared too
+    let mut cleanup_errors = Vec::new();
+    let scylla_tables = match scylla.reset_db().await {
+        Ok(tables) => {
+            tracing::debug!("[SycallaDb] : {} Reset", tables.join(" & "));
+            tables
+        }
+        Err(e) => {
+            tracing::error!("Unable to reset player cooldowns : {}", e);
+            cleanup_errors.push(e.to_string());
+            Vec::new()
+        }
+    };
+    match reset_users(app_state, redis).await {
+        Ok(credits) => tracing::debug!("[Redis] : {} credit buckets Reset", credits),
+        Err(e) => {
+            tracing::error!("Unable to reset pixel credits : {}", e);
+            cleanup_errors.push(e.to_string());
+        }
+    }
+    Ok(ResetReport {
+        generation,
+        previous_generation,
+        previous_version,
+        version,
+        redis_keys: vec![
+            app_state.canvas_key(generation),
+            app_state.generation_stats_key(generation),
+            app_state.painters_key(),
+            app_state.credits_pattern(),
+            app_state.flagged_key(),
+        ],
+        scylla_tables,
+        cleanup_errors,
+    })
+}
+
+// drop per-user redis state of ended generation , returns no. of credit buckets dropped
+async fn reset_users(app_state: &AppState<'_>, redis: &RedisManager) -> Result<usize, VpError> {
+    let mut conn = redis.clone();
+    let mut scan = redis::cmd("SCAN");
+    scan.cursor_arg(0)
+        .arg("MATCH")
+        .arg(app_state.credits_pattern())
+        .arg("COUNT")
+        .arg(RESET_BATCH);
+    let credits: Vec<String> = scan.iter_async::<String>(&mut conn).await?.collect().await;
+    let mut pipe = redis::pipe();
+    pipe.del(app_state.flagged_key()).ignore();
+    // batched so no single DEL blocks redis for long
+    for keys in credits.chunks(RESET_BATCH) {
+        pipe.del(keys).ignore();
+    }
+    pipe.query_async::<_, ()>(&mut conn).await?;
+    Ok(credits.len())
+}
+
+#[tracing::instrument(skip_all, fields(uid = %u_req.uid, x = u_req.loc.0, y = u_req.loc.1))]
 pub async fn update_place(
     u_req: &UpdatePixel,
     app_data: &AppState<'_>,
-    redis: &Client,
+    redis: &RedisManager,
     scylla: &ScyllaManager,
 
     pu_srv: &Addr<VpSrv<'_>>,
 ) -> Result<(), VpError> {
     // color size-> 16 colors [0,15], max val -> 15
-    if u_req.color <= 15 {
+    if u_req.color <= MAX_COLOR {
         if u_req.loc.0 < app_data.canvas_dim && u_req.loc.1 < app_data.canvas_dim {
             let offset: u32 = u_req.loc.0 * app_data.canvas_dim + u_req.loc.1;
-            // set redis bitmap
-            let mut conn = redis
-                .get_tokio_connection_manager()
-                .await
-                .map_err(VpError::RedisErr)?;
-            let redis_fut = async {
-                redis::cmd("bitfield")
-                    .arg(app_data.canvas_id.as_bytes())
-                    .arg("SET")
-                    .arg("u4")
-                    .arg(format!("#{}", offset))
-                    .arg(u_req.color)
-                    .query_async::<_, ()>(&mut conn)
+            // set redis bitmap of current generation
+            let mut conn = redis.clone();
+            // and bump canvas version along with it
+            let now = Utc::now().timestamp();
+            let (generation, version) = on_generation(app_data, |generation| {
+                let mut conn = redis.clone();
+                async move {
+                    redis_timed(
+                        "place",
+                        redis::Script::new(PLACE_PIXEL)
+                            .key(app_data.generation_key())
+                            .key(app_data.version_key())
+                            .key(app_data.painters_key())
+                            .key(app_data.pps_key(now))
+                            .key(app_data.canvas_key(generation))
+                            .key(app_data.generation_stats_key(generation))
+                            .key(app_data.generation_painters_key(generation))
+                            .arg(generation)
+                            .arg(offset)
+                            .arg(u_req.color)
+                            .arg(u_req.uid.to_string())
+                            .arg(now)
+                            .arg(PPS_TTL_SECS)
+                            .invoke_async::<_, (u64, Option<u64>)>(&mut conn),
+                    )
                     .await
-            }
-            .map_err(VpError::RedisErr);
+                }
+            })
+            .instrument(tracing::info_span!("redis.bitfield", offset))
+            .await?;
             // update user timestamp in scylladb
-            //also update pixeldata : )
-            let scylla_fut = scylla.update_db(u_req);
-
-            //execute both  database fut : )
-            tokio::try_join!(redis_fut, scylla_fut)?;
+            //also update pixeldata of the generation pixel landed in : )
+            // pixel is on canvas already, so it counts as placed even if scylla fails
+            // and listeners get it , else its version would be missing from every cache : )
+            if let Err(e) = scylla.update_db(u_req, generation).await {
+                tracing::error!("Unable to store pixel update : {}", e);
+                // cooldown without credits is read from player row , try it once more
+                if app_data.cooldown_policy.max_credits == 0 {
+                    if let Err(e) = scylla.update_user(u_req).await {
+                        tracing::error!("Unable to store last placement of {} : {}", u_req.uid, e);
+                    }
+                }
+            }
             // uid and uname not send to client : )
             // pixel based query will be added as different endpoint : )
-            log::debug!(
-                "updated color : {} for location ({},{}) ",
-                u_req.color,
-                u_req.loc.0,
-                u_req.loc.1
-            );
-            pu_srv.do_send(PlaceUpdate {
+            tracing::debug!(color = u_req.color, version, "pixel updated");
+            let update = PlaceUpdate {
                 loc: u_req.loc,
                 color: u_req.color,
-            });
+                version,
+                generation,
+            };
+            pu_srv.do_send(update.clone());
+            // pixel is already placed, so a failed publish only affects other instances : )
+            if let Err(e) = publish_event(VpEvent::Place(update), app_data, &mut conn)
+                .instrument(tracing::info_span!("redis.publish"))
+                .await
+            {
+                tracing::error!("Unable to publish pixel update : {}", e);
+            }
             Ok(())
         } else {
             Err(VpError::CanvasSizeMismatch)?
@@ -104,6 +433,445 @@ pub async fn update_place(
     }
 }
 
+async fn publish_event(
+    event: VpEvent,
+    app_data: &AppState<'_>,
+    conn: &mut RedisManager,
+) -> Result<(), VpError> {
+    let msg = PubEvent {
+        origin: app_data.instance_id,
+        event,
+    };
+    let payload = serde_json::to_string(&msg)?;
+    redis::cmd("PUBLISH")
+        .arg(app_data.update_channel())
+        .arg(payload)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+// send system event to listeners of all instances
+pub async fn broadcast_event(
+    event: SysEvent,
+    app_data: &AppState<'_>,
+    redis: &RedisManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+) -> Result<(), VpError> {
+    pu_srv.do_send(event.clone());
+    let mut conn = redis.clone();
+    publish_event(VpEvent::System { event }, app_data, &mut conn).await
+}
+
+// relay events published by other instances to local listeners
+async fn listen_place(
+    redis: &Client,
+    channel: &str,
+    instance_id: Uuid,
+    pu_srv: &Addr<VpSrv<'_>>,
+    cache: &CanvasCache,
+) -> Result<(), VpError> {
+    let mut pubsub = redis.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(channel).await?;
+    tracing::debug!("[Redis] : Subscribed to {}", channel);
+    // updates published while unsubscribed were missed
+    // pixel versions go with it , diffs since an older version are 410 until clients refetch
+    cache.invalidate();
+    let mut updates = pubsub.on_message();
+    while let Some(msg) = updates.next().await {
+        let payload = msg.get_payload::<String>()?;
+        match serde_json::from_str::<PubEvent>(&payload) {
+            Ok(msg) if msg.origin != instance_id => match msg.event {
+                VpEvent::Place(update) => pu_srv.do_send(update),
+                VpEvent::System { event } => pu_srv.do_send(event),
+                // presence is computed by every instance : )
+                VpEvent::Presence(_) => {}
+            },
+            Ok(_) => {}
+            Err(e) => tracing::error!("Invalid event on {} : {}", channel, e),
+        }
+    }
+    Ok(())
+}
+
+pub async fn subscribe_place(
+    redis: Client,
+    channel: String,
+    instance_id: Uuid,
+    pu_srv: Addr<VpSrv<'_>>,
+    cache: web::Data<CanvasCache>,
+) {
+    loop {
+        if let Err(e) = listen_place(&redis, &channel, instance_id, &pu_srv, &cache).await {
+            tracing::error!("[Redis] : Subscription to {} failed : {}", channel, e);
+        }
+        tokio::time::sleep(Duration::from_secs(SUBSCRIBE_RETRY_SECS)).await;
+    }
+}
+
+async fn presence(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+    interval: Duration,
+    window: i64,
+) -> Result<Presence, VpError> {
+    let local = pu_srv.send(VpCount).await.map_err(VpError::MailboxErr)?;
+    let mut conn = redis.clone();
+    let now = Utc::now().timestamp();
+    let (counts, painters) = redis::pipe()
+        .hset(
+            app_state.online_key(),
+            app_state.instance_id.to_string(),
+            format!("{}:{}", local, now),
+        )
+        .ignore()
+        .hgetall(app_state.online_key())
+        .zrembyscore(app_state.painters_key(), "-inf", now - window)
+        .ignore()
+        .zcard(app_state.painters_key())
+        .query_async::<_, (HashMap<String, String>, usize)>(&mut conn)
+        .await?;
+    // instances which missed a few ticks are considered dead : )
+    let expired = now - 3 * i64::try_from(interval.as_secs())?;
+    let mut online = 0;
+    let mut stale = Vec::new();
+    for (instance, count) in counts {
+        match count
+            .split_once(':')
+            .and_then(|(c, t)| Some((c.parse::<usize>().ok()?, t.parse::<i64>().ok()?)))
+        {
+            Some((count, updated)) if updated >= expired => online += count,
+            _ => stale.push(instance),
+        }
+    }
+    if !stale.is_empty() {
+        redis::Cmd::hdel(app_state.online_key(), stale)
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+    }
+    Ok(Presence { online, painters })
+}
+
+// periodically broadcast connected clients and active painters
+pub async fn track_presence(
+    app_state: web::Data<AppState<'_>>,
+    redis: RedisManager,
+    pu_srv: Addr<VpSrv<'_>>,
+    interval: Duration,
+    window: i64,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match presence(&app_state, &redis, &pu_srv, interval, window).await {
+            Ok(presence) => pu_srv.do_send(presence),
+            Err(e) => tracing::error!("Unable to update presence : {}", e),
+        }
+    }
+}
+
+pub async fn get_lifecycle(
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<CanvasLifecycle, VpError> {
+    let mut conn = redis.clone();
+    let lifecycle = redis_timed(
+        "lifecycle",
+        redis::Cmd::get(app_state.lifecycle_key()).query_async::<_, Option<String>>(&mut conn),
+    )
+    .await?;
+    // canvas without lifecycle is always open : )
+    match lifecycle {
+        Some(lifecycle) => Ok(serde_json::from_str(&lifecycle)?),
+        None => Ok(CanvasLifecycle::default()),
+    }
+}
+
+pub async fn set_lifecycle(
+    lifecycle: &CanvasLifecycle,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    redis::Cmd::set(app_state.lifecycle_key(), serde_json::to_string(lifecycle)?)
+        .query_async::<_, ()>(&mut conn)
+        .await?;
+    tracing::debug!(
+        "[Redis] : Canvas {} lifecycle set to {:?}",
+        app_state.canvas_id,
+        lifecycle.state
+    );
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn check_open(app_state: &AppState<'_>, redis: &RedisManager) -> Result<(), VpError> {
+    match get_lifecycle(app_state, redis)
+        .await?
+        .state_at(Utc::now().timestamp())
+    {
+        CanvasState::Open => Ok(()),
+        state => Err(VpError::CanvasNotOpen(state)),
+    }
+}
+
+// broadcast lifecycle transitions, including scheduled ones, to local listeners
+pub async fn watch_lifecycle(
+    app_state: web::Data<AppState<'_>>,
+    redis: RedisManager,
+    pu_srv: Addr<VpSrv<'_>>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(LIFECYCLE_POLL_SECS));
+    let mut last: Option<CanvasState> = None;
+    loop {
+        ticker.tick().await;
+        match get_lifecycle(&app_state, &redis).await {
+            Ok(lifecycle) => {
+                let state = lifecycle.state_at(Utc::now().timestamp());
+                if last.is_some_and(|last| last != state) {
+                    tracing::debug!("Canvas {} is now {:?}", app_state.canvas_id, state);
+                    pu_srv.do_send(SysEvent::StateChanged {
+                        state,
+                        start: lifecycle.start,
+                        end: lifecycle.end,
+                    });
+                }
+                last = Some(state);
+            }
+            Err(e) => tracing::error!("Unable to fetch canvas lifecycle : {}", e),
+        }
+    }
+}
+
+// cooldown of user `uid` placing at `loc`, after applying cooldown policy
+#[tracing::instrument(skip_all)]
+pub async fn effective_cooldown(
+    uid: Option<&Uuid>,
+    loc: Option<(u32, u32)>,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<usize, VpError> {
+    let mut conn = redis.clone();
+    let now = Utc::now().timestamp();
+    // empty field never has a tier : )
+    let uid = uid.map_or_else(String::new, Uuid::to_string);
+    let (base, pps, tier) = redis_timed(
+        "cooldown",
+        redis::pipe()
+            .get(app_state.cooldown_key())
+            .get(app_state.pps_key(now - 1))
+            .hget(app_state.tiers_key(), uid)
+            .query_async::<_, (Option<usize>, Option<u64>, Option<String>)>(&mut conn),
+    )
+    .await?;
+    Ok(app_state.cooldown_policy.apply(
+        base.unwrap_or(app_state.cooldown),
+        loc,
+        tier.as_deref(),
+        pps.unwrap_or(0),
+    ))
+}
+
+pub async fn set_cooldown(
+    cooldown: usize,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    redis::Cmd::set(app_state.cooldown_key(), cooldown)
+        .query_async::<_, ()>(&mut conn)
+        .await?;
+    tracing::debug!(
+        "[Redis] : Canvas {} cooldown set to {}",
+        app_state.canvas_id,
+        cooldown
+    );
+    broadcast_event(
+        SysEvent::CooldownChanged { cooldown },
+        app_state,
+        redis,
+        pu_srv,
+    )
+    .await
+}
+
+pub async fn set_tier(
+    uid: &Uuid,
+    tier: Option<&str>,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    match tier {
+        Some(tier) => {
+            redis::Cmd::hset(app_state.tiers_key(), uid.to_string(), tier)
+                .query_async::<_, ()>(&mut conn)
+                .await?
+        }
+        None => {
+            redis::Cmd::hdel(app_state.tiers_key(), uid.to_string())
+                .query_async::<_, ()>(&mut conn)
+                .await?
+        }
+    }
+    Ok(())
+}
+
+// spend a pixel credit of user, refilled once every `interval` secs
+#[tracing::instrument(skip_all)]
+async fn take_credit(
+    uid: &Uuid,
+    interval: usize,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(bool, Credits), VpError> {
+    let mut conn = redis.clone();
+    let max_credits = app_state.cooldown_policy.max_credits;
+    let (placed, credits, next_refill) = redis_timed(
+        "take_credit",
+        redis::Script::new(TAKE_CREDIT)
+            .key(app_state.credits_key(uid))
+            .arg(Utc::now().timestamp())
+            .arg(interval)
+            .arg(max_credits)
+            .invoke_async::<_, (u8, u64, i64)>(&mut conn),
+    )
+    .await?;
+    Ok((
+        placed.eq(&1),
+        Credits {
+            credits,
+            max_credits,
+            next_refill: (next_refill > 0).then_some(next_refill),
+        },
+    ))
+}
+
+// give back a credit spent on a failed pixel update
+async fn refund_credit(
+    uid: &Uuid,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    redis_timed(
+        "refund_credit",
+        redis::Script::new(REFUND_CREDIT)
+            .key(app_state.credits_key(uid))
+            .arg(app_state.cooldown_policy.max_credits)
+            .invoke_async::<_, ()>(&mut conn),
+    )
+    .await?;
+    Ok(())
+}
+
+// pixel update by user, checked against canvas lifecycle and cooldown (or pixel credits)
+#[tracing::instrument(skip_all, fields(uid = %u_req.uid))]
+pub async fn place_pixel(
+    u_req: &UpdatePixel,
+    app_data: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+    verifier: &dyn ChallengeVerifier,
+) -> Result<Placement, VpError> {
+    u_req
+        .validate(app_data.canvas_dim, &app_data.uname_rules)
+        .map_err(rejected)?;
+    check_open(app_data, redis).await.map_err(rejected)?;
+    let u_cooldown = effective_cooldown(Some(&u_req.uid), Some(u_req.loc), app_data, redis).await?;
+    if app_data.cooldown_policy.max_credits > 0 {
+        let (placed, credits) = take_credit(&u_req.uid, u_cooldown, app_data, redis).await?;
+        if placed {
+            match accept(u_req, app_data, redis, scylla, pu_srv, verifier).await {
+                Ok(true) => METRICS.placed("user"),
+                Ok(false) => {}
+                Err(e) => {
+                    refund_credit(&u_req.uid, app_data, redis).await?;
+                    return Err(e);
+                }
+            }
+            Ok(Placement::Placed(Some(credits)))
+        } else {
+            METRICS.reject("cooldown");
+            let rem_wait = credits
+                .next_refill
+                .map_or(0, |next_refill| next_refill - Utc::now().timestamp());
+            Ok(Placement::Wait(WaitTime {
+                rem_wait,
+                cooldown: u_cooldown,
+                credits: Some(credits),
+            }))
+        }
+    } else {
+        let cooldown = i64::try_from(u_cooldown)?;
+        let time_diff: i64 = diff_last_placed(&u_req.uid, u_cooldown, scylla).await?;
+        if time_diff.ge(&cooldown) {
+            if accept(u_req, app_data, redis, scylla, pu_srv, verifier).await? {
+                METRICS.placed("user");
+            }
+            Ok(Placement::Placed(None))
+        } else {
+            METRICS.reject("cooldown");
+            Ok(Placement::Wait(WaitTime {
+                rem_wait: cooldown - time_diff,
+                cooldown: u_cooldown,
+                credits: None,
+            }))
+        }
+    }
+}
+
+// pixel update allowed by cooldown , challenge is checked (and consumed) only now
+// so a proof isn't wasted on an update that has to wait
+// false if pixel update was dropped
+async fn accept(
+    u_req: &UpdatePixel,
+    app_data: &AppState<'_>,
+    redis: &RedisManager,
+    scylla: &ScyllaManager,
+    pu_srv: &Addr<VpSrv<'_>>,
+    verifier: &dyn ChallengeVerifier,
+) -> Result<bool, VpError> {
+    check_challenge(u_req, verifier, app_data, redis, scylla)
+        .await
+        .map_err(rejected)?;
+    // shadow-banned users get the usual cooldown and are told their pixel was placed : )
+    if is_shadowbanned(&u_req.uid, app_data, redis).await? {
+        tracing::debug!(
+            "[Raid] : dropped pixel update of shadow-banned {}",
+            u_req.uid
+        );
+        METRICS.reject("shadowbanned");
+        // last placement is still needed for cooldown without credits
+        if app_data.cooldown_policy.max_credits == 0 {
+            scylla.update_user(u_req).await?;
+        }
+        return Ok(false);
+    }
+    update_place(u_req, app_data, redis, scylla, pu_srv).await?;
+    Ok(true)
+}
+
+// count rejected pixel update by reason
+fn rejected(err: VpError) -> VpError {
+    match &err {
+        VpError::Validation(errs) => errs.iter().for_each(|e| {
+            METRICS.reject(match e.field {
+                "loc" => "bounds",
+                field => field,
+            })
+        }),
+        VpError::CanvasNotOpen(_) => METRICS.reject("canvas_not_open"),
+        VpError::ChallengeRequired | VpError::InvalidProof => METRICS.reject("challenge"),
+        _ => {}
+    }
+    err
+}
+
+#[tracing::instrument(skip_all)]
 pub async fn diff_last_placed(
     uid: &Uuid,
     cooldown: usize,
@@ -116,3 +884,211 @@ pub async fn diff_last_placed(
         Err(e) => Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::redis_models::script_stub::ScriptStub;
+
+    const KEY: &str = "canvas:credits:user";
+
+    // take a credit at `now` from a bucket of 3 credits refilled every 10s
+    fn take(redis: &ScriptStub, now: i64) -> (i64, i64, i64) {
+        redis.run(
+            TAKE_CREDIT,
+            &[KEY],
+            &[now.to_string(), "10".to_string(), "3".to_string()],
+        )
+    }
+
+    fn refund(redis: &ScriptStub) {
+        redis.run::<()>(REFUND_CREDIT, &[KEY], &["3".to_string()]);
+    }
+
+    #[test]
+    fn credits_burst_up_to_max() {
+        let redis = ScriptStub::new();
+        assert_eq!(take(&redis, 100), (1, 2, 110));
+        assert_eq!(take(&redis, 101), (1, 1, 110));
+        assert_eq!(take(&redis, 102), (1, 0, 110));
+        assert_eq!(take(&redis, 103), (0, 0, 110));
+    }
+
+    #[test]
+    fn credits_refill_per_interval() {
+        let redis = ScriptStub::new();
+        (0..3).for_each(|_| {
+            take(&redis, 100);
+        });
+        // 2 credits refilled , partial interval is kept for next refill
+        assert_eq!(take(&redis, 125), (1, 1, 130));
+        assert_eq!(take(&redis, 129), (1, 0, 130));
+        assert_eq!(take(&redis, 130), (1, 0, 140));
+    }
+
+    #[test]
+    fn credits_refill_capped_at_max() {
+        let redis = ScriptStub::new();
+        take(&redis, 100);
+        assert_eq!(take(&redis, 10_000), (1, 2, 10_010));
+    }
+
+    #[test]
+    fn refund_capped_at_max() {
+        let redis = ScriptStub::new();
+        // nothing to refund into an expired bucket
+        refund(&redis);
+        assert_eq!(redis.get(KEY, Some("credits")), None);
+        take(&redis, 100);
+        refund(&redis);
+        refund(&redis);
+        assert_eq!(redis.get(KEY, Some("credits")), Some(b"3".to_vec()));
+    }
+
+    // keys of canvas `c` , 8 pixels
+    const GEN_KEY: &str = "c:generation";
+    const VERSION_KEY: &str = "c:version";
+
+    fn gen_key(generation: u64, key: &str) -> String {
+        format!("c:gen:{}:{}", generation, key)
+    }
+
+    fn place(redis: &ScriptStub, generation: u64, offset: u32, color: u8) -> (u64, Option<u64>) {
+        let (canvas, stats, painters) = (
+            gen_key(generation, "canvas"),
+            gen_key(generation, "stats"),
+            gen_key(generation, "painters"),
+        );
+        redis.run(
+            PLACE_PIXEL,
+            &[
+                GEN_KEY,
+                VERSION_KEY,
+                "c:painters",
+                "c:pps",
+                &canvas,
+                &stats,
+                &painters,
+            ],
+            &[
+                generation.to_string(),
+                offset.to_string(),
+                color.to_string(),
+                "user".to_string(),
+                "100".to_string(),
+                "10".to_string(),
+            ],
+        )
+    }
+
+    fn reset(redis: &ScriptStub, generation: u64) -> (u64, Option<Vec<u64>>) {
+        let (stats, canvas, next_stats) = (
+            gen_key(generation, "stats"),
+            gen_key(generation + 1, "canvas"),
+            gen_key(generation + 1, "stats"),
+        );
+        redis.run(
+            RESET_CANVAS,
+            &[
+                GEN_KEY,
+                VERSION_KEY,
+                "c:painters",
+                &stats,
+                &canvas,
+                &next_stats,
+            ],
+            &[generation.to_string(), "4".to_string(), "100".to_string()],
+        )
+    }
+
+    fn init(redis: &ScriptStub, generation: u64) -> (u64, Option<u8>) {
+        let (canvas, stats) = (gen_key(generation, "canvas"), gen_key(generation, "stats"));
+        redis.run(
+            INIT_CANVAS,
+            &[GEN_KEY, &canvas, &stats, "c"],
+            &[generation.to_string(), "4".to_string(), "100".to_string()],
+        )
+    }
+
+    #[test]
+    fn init_creates_blank_canvas() {
+        let redis = ScriptStub::new();
+        assert_eq!(init(&redis, 0), (0, Some(1)));
+        assert_eq!(redis.get(&gen_key(0, "canvas"), None), Some(vec![0; 4]));
+        assert_eq!(
+            redis.get(&gen_key(0, "stats"), Some("started_at")),
+            Some(b"100".to_vec())
+        );
+        // existing canvas is kept
+        place(&redis, 0, 0, 1);
+        init(&redis, 0);
+        assert_eq!(
+            redis.get(&gen_key(0, "canvas"), None),
+            Some(vec![0x10, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn place_on_current_generation() {
+        let redis = ScriptStub::new();
+        assert_eq!(place(&redis, 0, 1, 5), (0, Some(1)));
+        assert_eq!(place(&redis, 0, 6, 0xF), (0, Some(2)));
+        assert_eq!(
+            redis.get(&gen_key(0, "canvas"), None),
+            Some(vec![0x05, 0, 0, 0xF0])
+        );
+        assert_eq!(
+            redis.get(&gen_key(0, "stats"), Some("placements")),
+            Some(b"2".to_vec())
+        );
+    }
+
+    #[test]
+    fn generation_scripts_refuse_ended_generation() {
+        let redis = ScriptStub::new();
+        place(&redis, 0, 0, 1);
+        assert_eq!(reset(&redis, 0), (0, Some(vec![1, 2])));
+        assert_eq!(redis.get(GEN_KEY, None), Some(b"1".to_vec()));
+        // placement with keys of ended generation changes nothing
+        assert_eq!(place(&redis, 0, 0, 2), (1, None));
+        assert_eq!(redis.get(&gen_key(0, "canvas"), None), Some(vec![0x10]));
+        assert_eq!(redis.get(VERSION_KEY, None), Some(b"2".to_vec()));
+        assert_eq!(reset(&redis, 0), (1, None));
+        assert_eq!(init(&redis, 0), (1, None));
+        assert_eq!(place(&redis, 1, 0, 2), (1, Some(3)));
+        assert_eq!(
+            redis.get(&gen_key(1, "canvas"), None),
+            Some(vec![0x20, 0, 0, 0])
+        );
+        assert_eq!(
+            redis.get(&gen_key(0, "stats"), Some("final_version")),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[test]
+    fn load_canvas_of_current_generation() {
+        let redis = ScriptStub::new();
+        init(&redis, 0);
+        place(&redis, 0, 0, 3);
+        // {canvas, version} as strings
+        let load = |generation: u64| -> (u64, Option<Vec<String>>) {
+            let canvas = gen_key(generation, "canvas");
+            redis.run(
+                LOAD_CANVAS,
+                &[GEN_KEY, VERSION_KEY, &canvas],
+                &[generation.to_string()],
+            )
+        };
+        let loaded = |canvas: &[u8], version: &str| {
+            Some(vec![
+                String::from_utf8(canvas.to_vec()).unwrap(),
+                version.to_string(),
+            ])
+        };
+        assert_eq!(load(0), (0, loaded(&[0x30, 0, 0, 0], "1")));
+        reset(&redis, 0);
+        assert_eq!(load(0), (1, None));
+        assert_eq!(load(1), (1, loaded(&[0; 4], "2")));
+    }
+}
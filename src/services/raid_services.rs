@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::models::err_models::VpError;
+use crate::models::metrics_models::redis_timed;
+use crate::models::p_models::AppState;
+use crate::models::raid_models::{Flag, RaidDetector};
+use crate::models::redis_models::RedisManager;
+
+// max. flags kept in moderation queue
+const MODQ_LEN: isize = 1000;
+
+async fn report(
+    flags: &[Flag],
+    detector: &RaidDetector,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    let mut pipe = redis::pipe();
+    for flag in flags {
+        tracing::info!(
+            "[Raid] : {:?} flagged for {} uids",
+            flag.reason,
+            flag.uids.len()
+        );
+        let uids: Vec<String> = flag.uids.iter().map(Uuid::to_string).collect();
+        pipe.lpush(app_state.modq_key(), serde_json::to_string(flag)?)
+            .ignore()
+            .sadd(app_state.flagged_key(), &uids)
+            .ignore();
+        if detector.conf.auto_shadowban {
+            pipe.sadd(app_state.shadowban_key(), &uids).ignore();
+        }
+    }
+    pipe.ltrim(app_state.modq_key(), 0, MODQ_LEN - 1)
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await?;
+    Ok(())
+}
+
+// first placement (ms) of uid , recorded as `now` if it's the first one
+async fn first_seen(
+    uid: &Uuid,
+    now: i64,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<i64, VpError> {
+    let mut conn = redis.clone();
+    let (first,): (i64,) = redis::pipe()
+        .atomic()
+        .hset_nx(app_state.first_seen_key(), uid.to_string(), now)
+        .ignore()
+        .hget(app_state.first_seen_key(), uid.to_string())
+        .query_async(&mut conn)
+        .await?;
+    Ok(first)
+}
+
+// feed a pixel update to raid detector, flags go to moderation queue
+pub async fn watch_placement(
+    uid: Uuid,
+    loc: (u32, u32),
+    ip: Option<IpAddr>,
+    detector: &RaidDetector,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) {
+    let now = Utc::now().timestamp_millis();
+    // unknown age doesn't count as new, new-account heuristics are skipped then
+    let first = match first_seen(&uid, now, app_state, redis).await {
+        Ok(first) => Some(first),
+        Err(e) => {
+            tracing::error!("Unable to get first placement of {} : {}", uid, e);
+            None
+        }
+    };
+    let flags = detector.observe(uid, first, ip, loc, now);
+    if flags.is_empty() {
+        return;
+    }
+    if let Err(e) = report(&flags, detector, app_state, redis).await {
+        tracing::error!("Unable to report raid flags : {}", e);
+    }
+}
+
+pub async fn moderation_queue(
+    count: usize,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<Vec<Flag>, VpError> {
+    let count = isize::try_from(count)
+        .unwrap_or(MODQ_LEN)
+        .clamp(1, MODQ_LEN);
+    let mut conn = redis.clone();
+    let flags = redis::Cmd::lrange(app_state.modq_key(), 0, count - 1)
+        .query_async::<_, Vec<String>>(&mut conn)
+        .await?;
+    Ok(flags
+        .iter()
+        .filter_map(|flag| serde_json::from_str(flag).ok())
+        .collect())
+}
+
+pub async fn is_shadowbanned(
+    uid: &Uuid,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<bool, VpError> {
+    let mut conn = redis.clone();
+    Ok(redis_timed(
+        "shadowban",
+        redis::Cmd::sismember(app_state.shadowban_key(), uid.to_string())
+            .query_async::<_, bool>(&mut conn),
+    )
+    .await?)
+}
+
+pub async fn set_shadowban(
+    uid: &Uuid,
+    banned: bool,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<(), VpError> {
+    let mut conn = redis.clone();
+    let mut pipe = redis::pipe();
+    if banned {
+        pipe.sadd(app_state.shadowban_key(), uid.to_string())
+    } else {
+        // unbanned users are no longer suspicious , drop the challenge requirement too
+        pipe.srem(app_state.shadowban_key(), uid.to_string())
+            .ignore()
+            .srem(app_state.flagged_key(), uid.to_string())
+    }
+    .ignore()
+    .query_async::<_, ()>(&mut conn)
+    .await?;
+    tracing::debug!("[Redis] : {} shadow-ban set to {}", uid, banned);
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+
+use chrono::Utc;
+
+use crate::models::err_models::VpError;
+use crate::models::metrics_models::{redis_timed, METRICS};
+use crate::models::p_models::AppState;
+use crate::models::rate_models::RateLimitConfig;
+use crate::models::redis_models::RedisManager;
+
+// fixed window counters per ip and subnet
+// returns seconds until the window resets if ip is rate limited
+pub async fn check_rate(
+    ip: IpAddr,
+    conf: &RateLimitConfig,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Result<Option<u64>, VpError> {
+    let mut conn = redis.clone();
+    let now = u64::try_from(Utc::now().timestamp())?;
+    let window = conf.window.max(1);
+    let ip_key = app_state.rate_key(&ip.to_string(), now / window);
+    let subnet_key = app_state.rate_key(&conf.subnet(ip), now / window);
+    let ttl = usize::try_from(window)?;
+    let (ip_count, subnet_count) = redis_timed(
+        "rate_limit",
+        redis::pipe()
+            .incr(&ip_key, 1)
+            .expire(&ip_key, ttl)
+            .ignore()
+            .incr(&subnet_key, 1)
+            .expire(&subnet_key, ttl)
+            .ignore()
+            .query_async::<_, (u64, u64)>(&mut conn),
+    )
+    .await?;
+    let limited = (conf.ip_limit > 0 && ip_count > conf.ip_limit)
+        || (conf.subnet_limit > 0 && subnet_count > conf.subnet_limit);
+    Ok(limited.then_some(window - now % window))
+}
+
+// rate limit is not enforced when redis is unavailable : )
+pub async fn rate_limited(
+    ip: Option<IpAddr>,
+    conf: &RateLimitConfig,
+    app_state: &AppState<'_>,
+    redis: &RedisManager,
+) -> Option<u64> {
+    if !conf.is_enabled() {
+        return None;
+    }
+    match check_rate(ip?, conf, app_state, redis).await {
+        Ok(Some(retry_after)) => {
+            METRICS.reject("rate_limited");
+            Some(retry_after)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            tracing::error!("Unable to check rate limit : {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+// tracing subscriber, `log` records are forwarded to it as events : )
+// span timings are logged on close, RUST_LOG overrides the default `debug` filter
+pub fn init_tracing() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_span_events(FmtSpan::CLOSE));
+    #[cfg(feature = "otlp")]
+    let (otlp, otlp_err) = match otlp::layer() {
+        Ok(layer) => (layer, None),
+        Err(e) => (None, Some(e)),
+    };
+    #[cfg(feature = "otlp")]
+    let registry = registry.with(otlp);
+    registry.init();
+    // reported once subscriber is installed
+    #[cfg(feature = "otlp")]
+    if let Some(e) = otlp_err {
+        tracing::warn!("Unable to start OTLP exporter : {}", e);
+    }
+}
+
+// flush spans not yet exported
+pub fn shutdown_tracing() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use std::env;
+
+    use opentelemetry::trace::TraceError;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{self, Tracer};
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    // export spans to OTLP collector at OTLP_ENDPOINT , disabled when unset
+    pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, Tracer>>, TraceError>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let Some(endpoint) = env::var("OTLP_ENDPOINT").ok().filter(|e| !e.is_empty()) else {
+            return Ok(None);
+        };
+        // continue traces started by clients (traceparent header)
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer =
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", "v-place"),
+                ])))
+                .install_batch(runtime::Tokio);
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer?)))
+    }
+}